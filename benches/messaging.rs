@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use libp2p::core::transport::MemoryTransport;
+use libp2p::core::upgrade::Version;
+use libp2p::futures::executor::block_on;
+use libp2p::futures::io::Cursor;
+use libp2p::futures::{AsyncRead, AsyncWrite, StreamExt};
+use libp2p::swarm::SwarmEvent;
+use libp2p::{plaintext, yamux, PeerId, StreamProtocol, Swarm, SwarmBuilder, Transport};
+use libp2p_messaging::bytes::BytesCodec;
+use libp2p_messaging::{Behaviour, Codec, Config, ConfigBuilder, Event, Framing};
+use std::io;
+use std::time::Duration;
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/messaging-bench/1.0.0");
+const MESSAGE_SIZES: [usize; 4] = [64, 1024, 64 * 1024, 1024 * 1024];
+
+/// Wraps [`BytesCodec`] without its [`Codec::encode_to_bytes`] override, to compare the default
+/// implementation, which encodes into a new buffer, with the zero-copy one.
+#[derive(Debug, Default)]
+struct CopyingCodec(BytesCodec);
+
+#[async_trait]
+impl Codec for CopyingCodec {
+    type Message = Bytes;
+
+    async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        self.0.decode_from(reader).await
+    }
+
+    async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        self.0.encode_to(writer, message).await
+    }
+
+    fn framing(&self) -> Framing {
+        self.0.framing()
+    }
+}
+
+fn codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec");
+    for size in MESSAGE_SIZES {
+        let message = Bytes::from(vec![0xab; size]);
+        let mut encoded = Cursor::new(Vec::with_capacity(size + 4));
+        block_on(BytesCodec.encode_to(&mut encoded, message.clone())).expect("encode message");
+        let encoded = encoded.into_inner();
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("encode", size), &message, |b, message| {
+            let mut buf = Vec::with_capacity(size + 4);
+            b.iter(|| {
+                buf.clear();
+                let mut writer = Cursor::new(&mut buf);
+                block_on(BytesCodec.encode_to(&mut writer, message.clone())).expect("encode");
+            });
+        });
+        group.bench_with_input(
+            BenchmarkId::new("encode_to_bytes_default", size),
+            &message,
+            |b, message| {
+                b.iter(|| {
+                    block_on(CopyingCodec::default().encode_to_bytes(message.clone()))
+                        .expect("encode")
+                });
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("encode_to_bytes", size),
+            &message,
+            |b, message| {
+                b.iter(|| block_on(BytesCodec.encode_to_bytes(message.clone())).expect("encode"));
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("decode", size), &encoded, |b, encoded| {
+            b.iter(|| {
+                let mut reader = Cursor::new(encoded.as_slice());
+                block_on(BytesCodec.decode_from(&mut reader)).expect("decode")
+            });
+        });
+    }
+    group.finish();
+}
+
+fn base_config() -> ConfigBuilder {
+    Config::builder().max_concurrent_streams(64)
+}
+
+fn new_swarm(config: Config) -> Swarm<Behaviour<BytesCodec>> {
+    SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_other_transport(|key| {
+            MemoryTransport::default()
+                .upgrade(Version::V1)
+                .authenticate(plaintext::Config::new(key))
+                .multiplex(yamux::Config::default())
+        })
+        .expect("memory transport is infallible")
+        .with_behaviour(|_| Behaviour::new([PROTOCOL], config).expect("valid config"))
+        .expect("behaviour is infallible")
+        .with_swarm_config(|config| config.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build()
+}
+
+/// A sender and a receiver swarm connected over the memory transport.
+struct Harness {
+    sender: Swarm<Behaviour<BytesCodec>>,
+    receiver: Swarm<Behaviour<BytesCodec>>,
+    receiver_id: PeerId,
+}
+
+impl Harness {
+    async fn connect(config: impl Fn() -> Config) -> Self {
+        let mut sender = new_swarm(config());
+        let mut receiver = new_swarm(config());
+        let receiver_id = *receiver.local_peer_id();
+
+        receiver
+            .listen_on("/memory/0".parse().unwrap())
+            .expect("listen on memory address");
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = receiver.select_next_some().await {
+                break address;
+            }
+        };
+        sender.dial(address).expect("dial receiver");
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { .. } = event {
+                        break;
+                    }
+                }
+                _ = receiver.select_next_some() => {}
+            }
+        }
+
+        Self {
+            sender,
+            receiver,
+            receiver_id,
+        }
+    }
+
+    /// Sends the messages and waits until the receiver has received all of them.
+    async fn exchange(&mut self, messages: impl IntoIterator<Item = Bytes>) {
+        let mut remaining = 0;
+        for message in messages {
+            self.sender
+                .behaviour_mut()
+                .send_message(self.receiver_id, message);
+            remaining += 1;
+        }
+        while remaining > 0 {
+            tokio::select! {
+                event = self.sender.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::OutboundFailure { error, .. }) = event {
+                        panic!("failed to send message: {error}");
+                    }
+                }
+                event = self.receiver.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::ReceivedMessage { .. }) = event {
+                        remaining -= 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn end_to_end(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime");
+    let mut harness = runtime.block_on(Harness::connect(|| {
+        base_config().build().expect("valid config")
+    }));
+
+    let mut group = c.benchmark_group("latency");
+    for size in MESSAGE_SIZES {
+        let message = Bytes::from(vec![0xab; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter(|| runtime.block_on(harness.exchange([message.clone()])));
+        });
+    }
+    group.finish();
+
+    // Small enough that the receiver never has more inbound streams than it accepts
+    const BATCH_SIZE: usize = 32;
+    let mut group = c.benchmark_group("messages_per_sec");
+    for size in [64, 1024] {
+        let message = Bytes::from(vec![0xab; size]);
+        group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter(|| {
+                runtime.block_on(harness.exchange(std::iter::repeat_n(message.clone(), BATCH_SIZE)))
+            });
+        });
+    }
+    group.finish();
+
+    // Batched messages share a stream, which the receiver reads with and without read-ahead
+    let mut group = c.benchmark_group("read_ahead");
+    for (name, read_ahead) in [("disabled", 0), ("enabled", 64 * 1024)] {
+        let mut harness = runtime.block_on(Harness::connect(|| {
+            base_config()
+                .batch_window(Some(Duration::from_micros(50)))
+                .inbound_read_ahead(read_ahead)
+                .build()
+                .expect("valid config")
+        }));
+        for size in [64, 1024] {
+            let message = Bytes::from(vec![0xab; size]);
+            group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+            group.bench_with_input(BenchmarkId::new(name, size), &message, |b, message| {
+                b.iter(|| {
+                    runtime.block_on(
+                        harness.exchange(std::iter::repeat_n(message.clone(), BATCH_SIZE)),
+                    )
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, codec, end_to_end);
+criterion_main!(benches);
@@ -1,19 +1,34 @@
 use crate::codec::Codec;
-use crate::error::Error;
+use crate::error::{ConfigError, Error};
 use crate::event::Event;
-use crate::handler::Handler;
-use crate::{Config, MessageId, OutboundMessage};
-use libp2p::core::Endpoint;
-use libp2p::swarm::dial_opts::DialOpts;
+use crate::handler::{Handler, HandlerIn, SharedState};
+#[cfg(feature = "testing")]
+use crate::HandlerDebugState;
+#[cfg(feature = "message-store")]
+use crate::MessageStore;
+use crate::{
+    priority_position, Config, ConnectionPriority, ConnectionStats, IdScope, InboundFilter,
+    MessageId, OutboundMessage, PeerStats, RequestId, SaturationPolicy, DEFAULT_PRIORITY,
+    MAX_BATCH_SIZE,
+};
+use futures_timer::Delay;
+use libp2p::core::{ConnectedPoint, Endpoint};
+use libp2p::futures::channel::{mpsc, oneshot};
+use libp2p::futures::{Future, FutureExt, Stream};
+use libp2p::swarm::behaviour::ConnectionEstablished;
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
 use libp2p::swarm::{
-    AddressChange, ConnectionClosed, ConnectionDenied, ConnectionHandler, ConnectionId,
-    DialFailure, FromSwarm, NetworkBehaviour, NotifyHandler, THandler, THandlerInEvent,
-    THandlerOutEvent, ToSwarm,
+    AddressChange, ConnectionClosed, ConnectionDenied, ConnectionId, DialFailure, FromSwarm,
+    NetworkBehaviour, NotifyHandler, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
 };
 use libp2p::{Multiaddr, PeerId, StreamProtocol};
 use smallvec::SmallVec;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+#[cfg(feature = "testing")]
+use std::sync::Mutex;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 /// Internal threshold for when to shrink the capacity
 /// of empty queues. If the capacity of an empty queue
@@ -26,7 +41,8 @@ pub struct Behaviour<TCodec>
 where
     TCodec: Codec + Send + Clone + 'static,
 {
-    protocol: StreamProtocol,
+    /// The supported protocols. The first protocol is used for outbound messages by default.
+    protocols: Vec<StreamProtocol>,
     config: Config,
     pending_events: VecDeque<ToSwarm<Event<TCodec::Message>, THandlerInEvent<Self>>>,
     pending_outbound_messages: HashMap<PeerId, SmallVec<OutboundMessage<TCodec::Message>, 10>>,
@@ -34,63 +50,1080 @@ where
     /// reachable addresses, if any.
     connected: HashMap<PeerId, SmallVec<Connection, 2>>,
     next_outbound_message_id: MessageId,
+    /// The next outbound message id for each peer, used when ids are scoped per peer.
+    next_peer_message_ids: HashMap<PeerId, MessageId>,
+    is_shutting_down: bool,
+    /// Whether outbound delivery is paused with [`Behaviour::pause_outbound`].
+    is_outbound_paused: bool,
+    /// Peers for which a flush was requested and that still have pending messages.
+    flushing_peers: HashSet<PeerId>,
+    /// Connected peers for which an additional connection is being dialed.
+    dialing_additional: HashSet<PeerId>,
+    /// Peers that are not connected and are being dialed to deliver queued messages.
+    dialing: HashSet<PeerId>,
+    event_sender: Option<mpsc::Sender<Event<TCodec::Message>>>,
+    peer_priorities: HashMap<PeerId, ConnectionPriority>,
+    /// Copies of in-flight messages sent with [`Behaviour::send_message_retained`].
+    retained_payloads: HashMap<(PeerId, MessageId), TCodec::Message>,
+    /// Payloads of failed messages, oldest first, bounded by [`Config::retain_failed`].
+    failed_payloads: VecDeque<((PeerId, MessageId), TCodec::Message)>,
+    inbound_filter: Option<InboundFilter<TCodec::Message>>,
+    local_peer_id: Option<PeerId>,
+    /// Addresses given to [`Behaviour::send_message_to_addr`], used when dialing the peer.
+    peer_addresses: HashMap<PeerId, Vec<Multiaddr>>,
+    /// The address each peer was last dialed at, bounded by [`Config::remembered_addresses`].
+    remembered_addresses: HashMap<PeerId, Multiaddr>,
+    /// Peers in `remembered_addresses`, least recently connected first.
+    remembered_address_order: VecDeque<PeerId>,
+    /// When peers dialed to send queued messages must be connected by, if `dial_timeout` is set
+    dial_deadlines: HashMap<PeerId, Instant>,
+    dial_timer: Option<(Instant, Delay)>,
+    /// Completes the futures returned by [`Behaviour::send_message_awaitable`].
+    awaited_messages: HashMap<(PeerId, MessageId), oneshot::Sender<Result<MessageId, Error>>>,
+    /// Messages sent with [`Behaviour::send_message_redundant`] on more than one connection.
+    redundant_messages: HashMap<(PeerId, MessageId), RedundantDelivery>,
+    /// Used to estimate the size of outbound messages.
+    codec: TCodec,
+    shared_handler_state: Arc<SharedState>,
+    #[cfg(feature = "testing")]
+    handler_states: HashMap<ConnectionId, Arc<Mutex<HandlerDebugState>>>,
+    #[cfg(feature = "message-store")]
+    message_store: Option<Box<dyn MessageStore<TCodec::Message>>>,
+    /// Messages loaded from the message store that are queued or in flight. They are removed from
+    /// the store once sent, and loaded again on the next connection if sending fails.
+    #[cfg(feature = "message-store")]
+    stored_messages: HashSet<(PeerId, MessageId)>,
+    /// Peers with stored messages that were left in the store because their pending queue was
+    /// full, loaded once the queue has room.
+    #[cfg(feature = "message-store")]
+    unloaded_stored_messages: HashSet<PeerId>,
 }
 
 impl<TCodec> Behaviour<TCodec>
 where
     TCodec: Codec + Send + Clone + 'static,
 {
-    pub fn new(protocol: StreamProtocol, config: Config) -> Self {
+    /// Creates a new behaviour that supports the given protocols. Inbound and outbound substreams
+    /// are negotiated with any of the protocols, in the order given by
+    /// [`Config::protocol_preference`], unless [`Behaviour::send_message_with_protocol`] is used.
+    ///
+    /// Returns an error if no protocols are given or the config is invalid.
+    pub fn new<I>(protocols: I, config: Config) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = StreamProtocol>,
+        TCodec: Default,
+    {
+        Self::with_codec(protocols, TCodec::default(), config)
+    }
+
+    /// Creates a new behaviour like [`Behaviour::new`], using the given codec instead of the
+    /// codec's default. Every connection's handler uses a clone of the codec.
+    pub fn with_codec<I>(protocols: I, codec: TCodec, config: Config) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = StreamProtocol>,
+    {
+        let protocols = protocols.into_iter().collect::<Vec<_>>();
+        if protocols.is_empty() {
+            return Err(ConfigError::NoProtocols);
+        }
+        config.validate()?;
+        let protocols = order_by_preference(
+            protocols,
+            &config.protocol_preference,
+            config.prefer_highest_version,
+        );
+        Ok(Self::new_unchecked(protocols, codec, config))
+    }
+
+    /// Creates a new behaviour for a single protocol using the default [`Config`].
+    pub fn with_protocol(protocol: StreamProtocol) -> Self
+    where
+        TCodec: Default,
+    {
+        Self::new_unchecked(vec![protocol], TCodec::default(), Config::default())
+    }
+
+    fn new_unchecked(protocols: Vec<StreamProtocol>, codec: TCodec, config: Config) -> Self {
+        let shared_handler_state = Arc::new(SharedState::new(&config));
+        let initial_message_id = config.initial_message_id;
         Self {
-            protocol,
+            protocols,
             config,
             pending_events: VecDeque::new(),
             pending_outbound_messages: HashMap::new(),
             connected: HashMap::new(),
-            next_outbound_message_id: 0,
+            next_outbound_message_id: initial_message_id,
+            next_peer_message_ids: HashMap::new(),
+            is_shutting_down: false,
+            is_outbound_paused: false,
+            flushing_peers: HashSet::new(),
+            dialing_additional: HashSet::new(),
+            dialing: HashSet::new(),
+            event_sender: None,
+            peer_priorities: HashMap::new(),
+            retained_payloads: HashMap::new(),
+            failed_payloads: VecDeque::new(),
+            inbound_filter: None,
+            local_peer_id: None,
+            peer_addresses: HashMap::new(),
+            remembered_addresses: HashMap::new(),
+            remembered_address_order: VecDeque::new(),
+            dial_deadlines: HashMap::new(),
+            dial_timer: None,
+            awaited_messages: HashMap::new(),
+            redundant_messages: HashMap::new(),
+            codec,
+            shared_handler_state,
+            #[cfg(feature = "testing")]
+            handler_states: HashMap::new(),
+            #[cfg(feature = "message-store")]
+            message_store: None,
+            #[cfg(feature = "message-store")]
+            stored_messages: HashSet::new(),
+            #[cfg(feature = "message-store")]
+            unloaded_stored_messages: HashSet::new(),
+        }
+    }
+
+    /// Sets a filter that is applied to inbound messages and requests once they are decoded.
+    /// Messages for which the filter returns false are dropped and reported as
+    /// [`Event::InboundRejected`] instead.
+    pub fn with_inbound_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&PeerId, &TCodec::Message) -> bool + Send + Sync + 'static,
+    {
+        self.inbound_filter = Some(InboundFilter::new(filter));
+        self
+    }
+
+    /// Sets the peer id of the local node. Messages sent to it fail immediately with
+    /// [`Error::SelfDial`] instead of the swarm attempting to dial itself.
+    pub fn with_local_peer_id(mut self, local_peer_id: PeerId) -> Self {
+        self.local_peer_id = Some(local_peer_id);
+        self
+    }
+
+    /// Sets a [`MessageStore`] that messages are persisted to when the pending queue for a peer is
+    /// full, instead of failing them with [`Error::QueueFull`].
+    #[cfg(feature = "message-store")]
+    pub fn with_message_store<S>(mut self, store: S) -> Self
+    where
+        S: MessageStore<TCodec::Message> + 'static,
+    {
+        self.message_store = Some(Box::new(store));
+        self
+    }
+
+    /// Begins shutting down the behaviour. Inbound messages that arrive after this call are handled
+    /// according to [`Config::inbound_during_shutdown`]. Outbound messages that are already pending
+    /// continue to be sent.
+    pub fn shutdown(&mut self) {
+        if self.is_shutting_down {
+            return;
         }
+        self.is_shutting_down = true;
+        for (peer_id, connections) in &self.connected {
+            for conn in connections {
+                self.pending_events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: *peer_id,
+                    handler: NotifyHandler::One(conn.id),
+                    event: HandlerIn::Shutdown,
+                });
+            }
+        }
+    }
+
+    /// Removes all messages that are queued for peers and returns their ids, along with those of
+    /// messages that are in flight on a connection, after which [`Behaviour::has_pending`] is false
+    /// for every peer. Intended to be called before the behaviour is dropped, since no events are
+    /// emitted for messages that are still pending at that point. The behaviour should not be used
+    /// afterwards.
+    pub fn drain_pending(&mut self) -> Vec<(PeerId, MessageId)> {
+        let mut pending = self
+            .pending_outbound_messages
+            .drain()
+            .flat_map(|(peer_id, messages)| {
+                messages
+                    .into_iter()
+                    .map(move |message| (peer_id, message.message_id))
+            })
+            .collect::<Vec<_>>();
+        let mut in_flight = HashSet::new();
+        for (peer_id, connections) in &mut self.connected {
+            for conn in connections {
+                for message_id in conn.pending_messages.drain() {
+                    // Redundant messages are in flight on several connections but reported once
+                    if in_flight.insert((*peer_id, message_id)) {
+                        pending.push((*peer_id, message_id));
+                    }
+                }
+            }
+        }
+        self.dial_deadlines.clear();
+        self.dial_timer = None;
+        self.retained_payloads.clear();
+        self.awaited_messages.clear();
+        self.redundant_messages.clear();
+        pending
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.is_shutting_down
+    }
+
+    /// Pauses outbound delivery. Messages sent while paused are queued for their peers, and peers
+    /// that are not connected are still dialed, but no message is handed to a connection until
+    /// [`Behaviour::resume_outbound`] is called. Messages already handed to a connection and
+    /// inbound messages are unaffected.
+    pub fn pause_outbound(&mut self) {
+        self.is_outbound_paused = true;
+    }
+
+    /// Resumes outbound delivery paused with [`Behaviour::pause_outbound`], dispatching the
+    /// messages queued for connected peers.
+    pub fn resume_outbound(&mut self) {
+        if !self.is_outbound_paused {
+            return;
+        }
+        self.is_outbound_paused = false;
+        let connected = self.connected.keys().copied().collect::<Vec<_>>();
+        for peer_id in connected {
+            self.dispatch_queued_messages(&peer_id);
+        }
+    }
+
+    pub fn is_outbound_paused(&self) -> bool {
+        self.is_outbound_paused
+    }
+
+    /// Replaces the config of the running behaviour.
+    ///
+    /// Most fields take effect immediately, including for existing connections. The exceptions are
+    /// `max_concurrent_streams` and `send_recv_timeout`: an existing connection only picks them up
+    /// once it has no streams in progress. Fields that change the wire format or message ids, or
+    /// that are shared by all connections, can't be changed and fail with
+    /// [`ConfigError::FixedAtRuntime`]. These are `max_total_concurrent_streams`,
+    /// `max_inbound_streams_per_peer`, `id_scope`, `initial_message_id`, `sequence_gap_detection`,
+    /// `require_ack`, `dedup_window`, `protocol_preference`, `prefer_highest_version` and
+    /// `request_protocols`.
+    pub fn set_config(&mut self, config: Config) -> Result<(), ConfigError> {
+        config.validate()?;
+        if let Some(field) = self.config.fixed_field_changed(&config) {
+            return Err(ConfigError::FixedAtRuntime(field));
+        }
+        while self.failed_payloads.len() > config.retain_failed {
+            self.failed_payloads.pop_front();
+        }
+        for (peer_id, connections) in &self.connected {
+            for conn in connections {
+                self.pending_events.push_back(ToSwarm::NotifyHandler {
+                    peer_id: *peer_id,
+                    handler: NotifyHandler::One(conn.id),
+                    event: HandlerIn::UpdateConfig(Box::new(config.clone())),
+                });
+            }
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    /// Sets how long connections to the peer are kept alive while idle. This applies to existing
+    /// and future connections.
+    pub fn set_peer_priority(&mut self, peer_id: PeerId, priority: ConnectionPriority) {
+        if priority == ConnectionPriority::default() {
+            self.peer_priorities.remove(&peer_id);
+        } else {
+            self.peer_priorities.insert(peer_id, priority);
+        }
+        for conn in self.connected.get(&peer_id).into_iter().flatten() {
+            self.pending_events.push_back(ToSwarm::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::One(conn.id),
+                event: HandlerIn::SetPriority(priority),
+            });
+        }
+    }
+
+    /// Requests that all pending messages for the peer are flushed. An [`Event::PeerFlushed`] is
+    /// emitted once every pending message to the peer has either been sent or has failed.
+    ///
+    /// Messages sent to the peer after calling this are still accepted and must also complete
+    /// before the flush is considered done.
+    pub fn flush_peer(&mut self, peer_id: &PeerId) {
+        self.flushing_peers.insert(*peer_id);
+        self.check_flushed(peer_id);
+    }
+
+    /// Cancels a message that is still queued for the peer, emitting [`Event::MessageCancelled`]
+    /// and returning true. Returns false if the message has already been handed to a connection,
+    /// has completed, or was persisted to a [`MessageStore`](crate::MessageStore).
+    pub fn cancel_message(&mut self, peer_id: &PeerId, message_id: MessageId) -> bool {
+        let Some(pending) = self.pending_outbound_messages.get_mut(peer_id) else {
+            return false;
+        };
+        let Some(ix) = pending.iter().position(|m| m.message_id == message_id) else {
+            return false;
+        };
+        pending.remove(ix);
+        self.retained_payloads.remove(&(*peer_id, message_id));
+        self.pending_events
+            .push_back(ToSwarm::GenerateEvent(Event::MessageCancelled {
+                peer_id: *peer_id,
+                message_id,
+            }));
+        self.check_flushed(peer_id);
+        true
+    }
+
+    /// Cancels all messages that are still queued for the peer, emitting an
+    /// [`Event::MessageCancelled`] for each and returning the number cancelled. Messages that have
+    /// already been handed to a connection are unaffected.
+    pub fn clear_pending(&mut self, peer_id: &PeerId) -> usize {
+        let Some(pending) = self.pending_outbound_messages.remove(peer_id) else {
+            return 0;
+        };
+        let num_cleared = pending.len();
+        for message in pending {
+            self.retained_payloads
+                .remove(&(*peer_id, message.message_id));
+            self.pending_events
+                .push_back(ToSwarm::GenerateEvent(Event::MessageCancelled {
+                    peer_id: *peer_id,
+                    message_id: message.message_id,
+                }));
+        }
+        self.check_flushed(peer_id);
+        num_cleared
+    }
+
+    /// Returns true if there are any messages to the peer that have not yet been sent or failed.
+    pub fn has_pending(&self, peer_id: &PeerId) -> bool {
+        self.pending_outbound_messages
+            .get(peer_id)
+            .is_some_and(|pending| !pending.is_empty())
+            || self.connected.get(peer_id).is_some_and(|connections| {
+                connections
+                    .iter()
+                    .any(|conn| !conn.pending_messages.is_empty())
+            })
+    }
+
+    /// Returns every peer for which [`Behaviour::has_pending`] is true.
+    pub fn peers_with_pending(&self) -> Vec<PeerId> {
+        self.pending_outbound_messages
+            .keys()
+            .chain(
+                self.connected
+                    .keys()
+                    .filter(|peer_id| !self.pending_outbound_messages.contains_key(peer_id)),
+            )
+            .filter(|peer_id| self.has_pending(peer_id))
+            .copied()
+            .collect()
+    }
+
+    /// Returns a stream of the events emitted by this behaviour. While the stream exists, events
+    /// are delivered to it instead of being returned from the swarm. The stream is bounded by
+    /// [`Config::event_stream_capacity`]; when it is full, the behaviour stops making progress until
+    /// the stream is polled, so events are never dropped. Calling this again ends the previous
+    /// stream, and dropping the stream resumes emitting events to the swarm.
+    pub fn event_stream(&mut self) -> impl Stream<Item = Event<TCodec::Message>> {
+        let (sender, receiver) = mpsc::channel(self.config.event_stream_capacity);
+        self.event_sender = Some(sender);
+        receiver
+    }
+
+    /// Returns the most recent debug state reported by each connection handler.
+    #[cfg(feature = "testing")]
+    pub fn handler_states(&self) -> Vec<HandlerDebugState> {
+        self.handler_states
+            .values()
+            .map(|state| state.lock().expect("debug state lock poisoned").clone())
+            .collect()
+    }
+
+    /// Returns the most recent debug state reported by the handler of the connection, including
+    /// the number of events it has buffered for the behaviour and its stream tasks in progress.
+    #[cfg(feature = "testing")]
+    pub fn handler_state(&self, connection_id: ConnectionId) -> Option<HandlerDebugState> {
+        self.handler_states
+            .get(&connection_id)
+            .map(|state| state.lock().expect("debug state lock poisoned").clone())
+    }
+
+    /// Returns a snapshot of the messaging state for the peer.
+    pub fn peer_stats(&self, peer_id: &PeerId) -> PeerStats {
+        PeerStats {
+            num_queued: self
+                .pending_outbound_messages
+                .get(peer_id)
+                .map_or(0, |pending| pending.len()),
+            connections: self
+                .connected
+                .get(peer_id)
+                .map(|connections| {
+                    connections
+                        .iter()
+                        .map(|conn| conn.stats(&self.shared_handler_state))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the ids of messages to the peer that have been handed to a connection but not yet
+    /// sent or failed. Messages that are still queued are counted by
+    /// [`PeerStats::num_queued`] instead. A message sent with
+    /// [`Behaviour::send_message_redundant`] is returned once for each connection it is in
+    /// flight on.
+    pub fn inflight_messages(&self, peer_id: &PeerId) -> impl Iterator<Item = MessageId> + '_ {
+        self.connected
+            .get(peer_id)
+            .into_iter()
+            .flatten()
+            .flat_map(|conn| conn.pending_messages.iter().copied())
+    }
+
+    fn check_flushed(&mut self, peer_id: &PeerId) {
+        if self.flushing_peers.contains(peer_id) && !self.has_pending(peer_id) {
+            self.flushing_peers.remove(peer_id);
+            self.pending_events
+                .push_back(ToSwarm::GenerateEvent(Event::PeerFlushed {
+                    peer_id: *peer_id,
+                }));
+        }
+    }
+
+    /// Sends a message to the peer, returning the id assigned to the message. If the peer is not
+    /// connected, the message is queued and the peer is dialed.
+    pub fn send_message(&mut self, peer_id: PeerId, message: TCodec::Message) -> MessageId {
+        self.send_message_inner(peer_id, message, SendOptions::default())
+    }
+
+    /// Sends a message to the peer with the given priority, returning the id assigned to the
+    /// message. Messages queued for the peer are sent in order of descending priority, so the
+    /// message is sent before any queued messages with a lower priority. Messages sent with
+    /// [`Behaviour::send_message`] have [`DEFAULT_PRIORITY`].
+    pub fn send_message_with_priority(
+        &mut self,
+        peer_id: PeerId,
+        priority: u8,
+        message: TCodec::Message,
+    ) -> MessageId {
+        self.send_message_inner(
+            peer_id,
+            message,
+            SendOptions {
+                priority,
+                ..SendOptions::default()
+            },
+        )
+    }
+
+    /// Sends a message to the peer, returning a future that resolves with the id of the message
+    /// once it has been sent, or with the error it failed with. The outcome is delivered to the
+    /// future instead of as an [`Event::MessageSent`], [`Event::MessageAcked`] or
+    /// [`Event::OutboundFailure`]. The swarm must still be polled for the message to be sent.
+    ///
+    /// If the message is cancelled or the behaviour is dropped first, the future resolves with
+    /// [`Error::ConnectionClosed`].
+    pub fn send_message_awaitable(
+        &mut self,
+        peer_id: PeerId,
+        message: TCodec::Message,
+    ) -> impl Future<Output = Result<MessageId, Error>> {
+        let message_id = self.send_message(peer_id, message);
+        let (sender, receiver) = oneshot::channel();
+        self.awaited_messages.insert((peer_id, message_id), sender);
+        receiver.map(|result| result.unwrap_or(Err(Error::ConnectionClosed)))
     }
 
-    pub fn send_message(&mut self, peer_id: PeerId, message: TCodec::Message) {
-        let message_id = self.next_outbound_message_id();
+    /// Sends a copy of the message on every connection to the peer, returning the single id
+    /// assigned to all of the copies. One [`Event::MessageSent`] (or [`Event::MessageAcked`]) is
+    /// emitted once the first copy is sent, and an [`Event::OutboundFailure`] only if every copy
+    /// fails. Copies are sent even if a connection is saturated.
+    ///
+    /// The receiver may receive the message once per connection. If the peer has at most one
+    /// connection, or outbound delivery is paused, this is the same as
+    /// [`Behaviour::send_message`].
+    pub fn send_message_redundant(&mut self, peer_id: PeerId, message: TCodec::Message) -> MessageId
+    where
+        TCodec::Message: Clone,
+    {
+        let connection_ids = self
+            .connected
+            .get(&peer_id)
+            .map(|connections| connections.iter().map(|c| c.id).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if connection_ids.len() < 2 || self.is_outbound_paused {
+            return self.send_message(peer_id, message);
+        }
+
+        let message_id = self.next_outbound_message_id(&peer_id);
+        self.redundant_messages.insert(
+            (peer_id, message_id),
+            RedundantDelivery {
+                remaining: connection_ids.len(),
+                is_delivered: false,
+            },
+        );
+        for connection_id in connection_ids {
+            if let Some(conn) = self.get_connection_mut(&peer_id, connection_id) {
+                conn.pending_messages.insert(message_id);
+            }
+            self.pending_events.push_back(ToSwarm::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::One(connection_id),
+                event: HandlerIn::Send(OutboundMessage {
+                    peer_id,
+                    protocol: None,
+                    message_id,
+                    message: message.clone(),
+                    is_request: false,
+                    dedup_key: None,
+                    priority: DEFAULT_PRIORITY,
+                    queued_at: Instant::now(),
+                    ttl: None,
+                }),
+            });
+        }
+        message_id
+    }
+
+    /// Records the outcome of one copy of a message sent with
+    /// [`Behaviour::send_message_redundant`]. Returns true if the outcome is to be emitted, which
+    /// is the case for the first success, or for the last failure if no copy succeeded.
+    fn on_redundant_outcome(
+        &mut self,
+        peer_id: PeerId,
+        message_id: MessageId,
+        is_success: bool,
+    ) -> bool {
+        let key = (peer_id, message_id);
+        let Some(delivery) = self.redundant_messages.get_mut(&key) else {
+            return true;
+        };
+        delivery.remaining = delivery.remaining.saturating_sub(1);
+        let should_emit = if is_success {
+            !std::mem::replace(&mut delivery.is_delivered, true)
+        } else {
+            !delivery.is_delivered && delivery.remaining == 0
+        };
+        if delivery.remaining == 0 {
+            self.redundant_messages.remove(&key);
+        }
+        should_emit
+    }
+
+    /// Sends a message to the peer, dialing it at the given address if it is not connected. The
+    /// address is kept and used, along with any addresses known to the swarm, whenever the peer is
+    /// dialed to send a message.
+    pub fn send_message_to_addr(
+        &mut self,
+        peer_id: PeerId,
+        address: Multiaddr,
+        message: TCodec::Message,
+    ) -> MessageId {
+        let addresses = self.peer_addresses.entry(peer_id).or_default();
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+        self.send_message(peer_id, message)
+    }
+
+    /// Sends a request to the peer using one of the [`Config::request_protocols`], returning the
+    /// id of the request. The response is emitted as an [`Event::Response`] with the same id.
+    pub fn request(&mut self, peer_id: PeerId, message: TCodec::Message) -> RequestId {
+        if self.config.request_protocols.is_empty() {
+            let request_id = self.next_outbound_message_id(&peer_id);
+            tracing::warn!("no request protocols are configured, failing request {request_id}");
+            self.pending_events
+                .push_back(ToSwarm::GenerateEvent(Event::OutboundFailure {
+                    peer_id,
+                    message_id: request_id,
+                    error: Error::ProtocolNotSupported,
+                }));
+            return request_id;
+        }
+        self.send_message_inner(
+            peer_id,
+            message,
+            SendOptions {
+                is_request: true,
+                ..SendOptions::default()
+            },
+        )
+    }
+
+    /// Sends a message to the peer on a substream negotiated with the given protocol, returning
+    /// the id assigned to the message.
+    pub fn send_message_with_protocol(
+        &mut self,
+        peer_id: PeerId,
+        protocol: StreamProtocol,
+        message: TCodec::Message,
+    ) -> MessageId {
+        self.send_message_inner(
+            peer_id,
+            message,
+            SendOptions {
+                protocol: Some(protocol),
+                ..SendOptions::default()
+            },
+        )
+    }
+
+    /// Sends a message with a key that the receiver uses to drop duplicates, returning the id of the
+    /// message. Sending the same key again, for example when retrying after a failure, delivers the
+    /// message at most once as long as the key is still within the receiver's
+    /// [`Config::dedup_window`]. Duplicates are reported to the receiver as
+    /// [`Event::DuplicateDropped`].
+    pub fn send_message_with_dedup_key(
+        &mut self,
+        peer_id: PeerId,
+        dedup_key: u64,
+        message: TCodec::Message,
+    ) -> MessageId {
+        self.send_message_inner(
+            peer_id,
+            message,
+            SendOptions {
+                dedup_key: Some(dedup_key),
+                ..SendOptions::default()
+            },
+        )
+    }
+
+    /// Sends a message to the peer that is dropped if it is still queued once `ttl` has elapsed,
+    /// for example while the peer is being dialed, returning the id of the message. An expired
+    /// message is reported as an [`Event::OutboundFailure`] with [`Error::Expired`]. Messages
+    /// already handed to a connection are not expired.
+    pub fn send_message_with_ttl(
+        &mut self,
+        peer_id: PeerId,
+        ttl: Duration,
+        message: TCodec::Message,
+    ) -> MessageId {
+        self.send_message_inner(
+            peer_id,
+            message,
+            SendOptions {
+                ttl: Some(ttl),
+                ..SendOptions::default()
+            },
+        )
+    }
+
+    fn send_message_inner(
+        &mut self,
+        peer_id: PeerId,
+        message: TCodec::Message,
+        options: SendOptions,
+    ) -> MessageId {
+        let message_id = self.next_outbound_message_id(&peer_id);
+        let _span = tracing::debug_span!("send_message", %peer_id, %message_id).entered();
+
+        if self.local_peer_id == Some(peer_id) {
+            tracing::warn!("attempted to send a message to the local peer");
+            self.pending_events
+                .push_back(ToSwarm::GenerateEvent(Event::OutboundFailure {
+                    peer_id,
+                    message_id,
+                    error: Error::SelfDial,
+                }));
+            return message_id;
+        }
+
+        if let Some(max) = self.config.max_message_size {
+            if let Some(size) = self
+                .codec
+                .estimate_size(&message)
+                .filter(|size| *size > max)
+            {
+                tracing::warn!("message of {size} bytes exceeds the maximum of {max} bytes");
+                self.pending_events
+                    .push_back(ToSwarm::GenerateEvent(Event::OutboundFailure {
+                        peer_id,
+                        message_id,
+                        error: Error::MessageTooLarge {
+                            len: size,
+                            limit: max,
+                        },
+                    }));
+                return message_id;
+            }
+        }
+
         let message = OutboundMessage {
             peer_id,
+            protocol: options.protocol,
             message_id,
             message,
+            is_request: options.is_request,
+            dedup_key: options.dedup_key,
+            priority: options.priority,
+            queued_at: Instant::now(),
+            ttl: options.ttl,
         };
 
         if let Some(message) = self.try_send_request(message) {
-            self.pending_events.push_back(ToSwarm::Dial {
-                opts: DialOpts::peer_id(peer_id).build(),
-            });
-            self.pending_outbound_messages
-                .entry(peer_id)
-                .or_default()
-                .push(message);
+            if let Some(connections) = self
+                .connected
+                .get(&peer_id)
+                .filter(|_| !self.is_outbound_paused)
+            {
+                match self.config.saturation_policy {
+                    SaturationPolicy::Queue => {}
+                    SaturationPolicy::OpenNewConnection { max_connections } => {
+                        if connections.len() < max_connections {
+                            self.dial_additional(peer_id);
+                        }
+                    }
+                    SaturationPolicy::Fail => {
+                        self.pending_events.push_back(ToSwarm::GenerateEvent(
+                            Event::OutboundFailure {
+                                peer_id,
+                                message_id,
+                                error: Error::AllConnectionsSaturated,
+                            },
+                        ));
+                        return message_id;
+                    }
+                }
+            }
+
+            let pending = self.pending_outbound_messages.entry(peer_id).or_default();
+            if pending.len() >= self.config.max_pending_outbound_per_peer {
+                #[cfg(feature = "message-store")]
+                if let Some(store) = self.message_store.as_mut() {
+                    if let Err(err) = store.persist(message) {
+                        self.pending_events.push_back(ToSwarm::GenerateEvent(
+                            Event::OutboundFailure {
+                                peer_id,
+                                message_id,
+                                error: Error::MessageStore(err),
+                            },
+                        ));
+                    }
+                    self.dial(peer_id);
+                    return message_id;
+                }
+
+                tracing::debug!(
+                    "outbound queue for peer {peer_id} is full, rejecting message {message_id}"
+                );
+                self.pending_events
+                    .push_back(ToSwarm::GenerateEvent(Event::OutboundFailure {
+                        peer_id,
+                        message_id,
+                        error: Error::QueueFull,
+                    }));
+                return message_id;
+            }
+            match priority_position(pending.iter(), message.priority) {
+                Some(ix) => pending.insert(ix, message),
+                None => pending.push(message),
+            }
+            // If connected, every connection is saturated and the message is sent once one
+            // has capacity
+            if !self.connected.contains_key(&peer_id) {
+                self.pending_events
+                    .push_back(ToSwarm::GenerateEvent(Event::OutboundQueued {
+                        peer_id,
+                        message_id,
+                    }));
+                self.dial(peer_id);
+            }
+        }
+
+        message_id
+    }
+
+    /// Dispatches queued messages for a connected peer until all of its connections are
+    /// saturated.
+    fn dispatch_queued_messages(&mut self, peer_id: &PeerId) {
+        #[cfg(feature = "message-store")]
+        if self.unloaded_stored_messages.contains(peer_id)
+            && self
+                .pending_outbound_messages
+                .get(peer_id)
+                .is_none_or(|pending| pending.len() < self.config.max_pending_outbound_per_peer)
+        {
+            self.load_stored_messages(*peer_id);
+        }
+        while let Some(message) = self
+            .pending_outbound_messages
+            .get_mut(peer_id)
+            .filter(|pending| !pending.is_empty())
+            .map(|pending| pending.remove(0))
+        {
+            if let Some(message) = self.try_send_request(message) {
+                self.pending_outbound_messages
+                    .entry(*peer_id)
+                    .or_default()
+                    .insert(0, message);
+                break;
+            }
+        }
+    }
+
+    /// Sends a message to the peer, keeping a copy of it so that it can be taken back with
+    /// [`Behaviour::take_failed`] if sending fails. Copies are only kept if
+    /// [`Config::retain_failed`] is non-zero.
+    pub fn send_message_retained(&mut self, peer_id: PeerId, message: TCodec::Message) -> MessageId
+    where
+        TCodec::Message: Clone,
+    {
+        if self.config.retain_failed == 0 {
+            return self.send_message(peer_id, message);
+        }
+        let message_id = self.send_message_inner(peer_id, message.clone(), SendOptions::default());
+        self.retained_payloads
+            .insert((peer_id, message_id), message);
+        message_id
+    }
+
+    /// Takes the payload of a message sent with [`Behaviour::send_message_retained`] that failed.
+    /// Only the most recent [`Config::retain_failed`] failed payloads are kept.
+    pub fn take_failed(
+        &mut self,
+        peer_id: &PeerId,
+        message_id: MessageId,
+    ) -> Option<TCodec::Message> {
+        let ix = self
+            .failed_payloads
+            .iter()
+            .position(|(key, _)| *key == (*peer_id, message_id))?;
+        self.failed_payloads.remove(ix).map(|(_, message)| message)
+    }
+
+    fn update_retained_payloads(&mut self, event: &Event<TCodec::Message>) {
+        if self.retained_payloads.is_empty() {
+            return;
+        }
+        match event {
+            Event::MessageSent {
+                peer_id,
+                message_id,
+                ..
+            }
+            | Event::MessageAcked {
+                peer_id,
+                message_id,
+                ..
+            } => {
+                self.retained_payloads.remove(&(*peer_id, *message_id));
+            }
+            Event::OutboundFailure {
+                peer_id,
+                message_id,
+                ..
+            } => self.retain_failed_payload(*peer_id, *message_id),
+            Event::BulkFailure {
+                peer_id,
+                message_ids,
+                ..
+            } => {
+                for message_id in message_ids {
+                    self.retain_failed_payload(*peer_id, *message_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Completes the future of an awaited message with the outcome in the event. Returns the event
+    /// if it is still to be emitted.
+    fn complete_awaited_message(
+        &mut self,
+        event: Event<TCodec::Message>,
+    ) -> Option<Event<TCodec::Message>> {
+        if self.awaited_messages.is_empty() {
+            return Some(event);
+        }
+        let (peer_id, message_id) = match &event {
+            Event::MessageSent {
+                peer_id,
+                message_id,
+                ..
+            }
+            | Event::MessageAcked {
+                peer_id,
+                message_id,
+                ..
+            }
+            | Event::OutboundFailure {
+                peer_id,
+                message_id,
+                ..
+            } => (*peer_id, *message_id),
+            Event::MessageCancelled {
+                peer_id,
+                message_id,
+            } => {
+                // Dropping the sender resolves the future
+                self.awaited_messages.remove(&(*peer_id, *message_id));
+                return Some(event);
+            }
+            Event::BulkFailure {
+                peer_id,
+                message_ids,
+                ..
+            } => {
+                let peer_id = *peer_id;
+                let is_awaited = |message_id: &MessageId| {
+                    self.awaited_messages.contains_key(&(peer_id, *message_id))
+                };
+                if !message_ids.iter().any(is_awaited) {
+                    return Some(event);
+                }
+                let Event::BulkFailure {
+                    message_ids,
+                    remote_address,
+                    error,
+                    ..
+                } = event
+                else {
+                    unreachable!("matched above")
+                };
+                let (awaited, message_ids): (Vec<_>, Vec<_>) = message_ids
+                    .into_iter()
+                    .partition(|message_id| is_awaited(message_id));
+                for message_id in awaited {
+                    if let Some(sender) = self.awaited_messages.remove(&(peer_id, message_id)) {
+                        let _ = sender.send(Err(Error::ConnectionClosed));
+                    }
+                }
+                return (!message_ids.is_empty()).then_some(Event::BulkFailure {
+                    peer_id,
+                    message_ids,
+                    remote_address,
+                    error,
+                });
+            }
+            _ => return Some(event),
+        };
+        let Some(sender) = self.awaited_messages.remove(&(peer_id, message_id)) else {
+            return Some(event);
+        };
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.config.metrics {
+            metrics.record(&event);
         }
+        let result = match event {
+            Event::OutboundFailure { error, .. } => Err(error),
+            _ => Ok(message_id),
+        };
+        let _ = sender.send(result);
+        None
     }
 
-    fn next_outbound_message_id(&mut self) -> MessageId {
-        let request_id = self.next_outbound_message_id;
-        self.next_outbound_message_id = self.next_outbound_message_id.wrapping_add(1);
-        request_id
+    fn retain_failed_payload(&mut self, peer_id: PeerId, message_id: MessageId) {
+        let Some(message) = self.retained_payloads.remove(&(peer_id, message_id)) else {
+            return;
+        };
+        if self.failed_payloads.len() >= self.config.retain_failed {
+            self.failed_payloads.pop_front();
+        }
+        self.failed_payloads
+            .push_back(((peer_id, message_id), message));
+    }
+
+    /// Sends a message to the peer only if `condition` returns true for the current
+    /// [`PeerStats`] of the peer. Returns the message id if the message was sent, otherwise `None`.
+    pub fn send_message_if<F>(
+        &mut self,
+        peer_id: PeerId,
+        message: TCodec::Message,
+        condition: F,
+    ) -> Option<MessageId>
+    where
+        F: FnOnce(&PeerStats) -> bool,
+    {
+        if !condition(&self.peer_stats(&peer_id)) {
+            return None;
+        }
+        Some(self.send_message(peer_id, message))
+    }
+
+    /// Allocates the next message id for the peer. Once the counter wraps, ids that are still in
+    /// flight to the peer are skipped so that events for the two messages can't be confused.
+    fn next_outbound_message_id(&mut self, peer_id: &PeerId) -> MessageId {
+        loop {
+            let next_id = match self.config.id_scope {
+                IdScope::Global => &mut self.next_outbound_message_id,
+                IdScope::PerPeer => self.next_peer_message_ids.entry(*peer_id).or_default(),
+            };
+            let message_id = *next_id;
+            *next_id = next_id.wrapping_next();
+            if !self.is_message_in_flight(peer_id, message_id) {
+                return message_id;
+            }
+            tracing::warn!(
+                "message id {message_id} for peer {peer_id} is still in flight, skipping"
+            );
+        }
+    }
+
+    fn is_message_in_flight(&self, peer_id: &PeerId, message_id: MessageId) -> bool {
+        let is_queued = self
+            .pending_outbound_messages
+            .get(peer_id)
+            .is_some_and(|pending| pending.iter().any(|m| m.message_id == message_id));
+        is_queued
+            || self.connected.get(peer_id).is_some_and(|connections| {
+                connections
+                    .iter()
+                    .any(|conn| conn.pending_messages.contains(&message_id))
+            })
     }
 
     fn try_send_request(
         &mut self,
         message: OutboundMessage<TCodec::Message>,
     ) -> Option<OutboundMessage<TCodec::Message>> {
+        if self.is_outbound_paused {
+            return Some(message);
+        }
+        if message.is_expired(Instant::now()) {
+            tracing::debug!(
+                "message {} for peer {} expired before it was sent",
+                message.message_id,
+                message.peer_id
+            );
+            self.pending_events
+                .push_back(ToSwarm::GenerateEvent(Event::OutboundFailure {
+                    peer_id: message.peer_id,
+                    message_id: message.message_id,
+                    error: Error::Expired,
+                }));
+            return None;
+        }
         if let Some(connections) = self.connected.get_mut(&message.peer_id) {
             if connections.is_empty() {
                 return Some(message);
             }
-            let ix = (message.message_id as usize) % connections.len();
-            let conn = &mut connections[ix];
+            // Starting from a connection chosen by message id, pick the first connection that is
+            // not saturated with in-flight messages.
+            let num_connections = connections.len();
+            let start = message.message_id.as_usize() % num_connections;
+            // Batched messages share a stream, so a connection can have more messages in flight
+            let max_in_flight = match self.config.batch_window {
+                Some(_) => self.config.max_concurrent_streams * MAX_BATCH_SIZE,
+                None => self.config.max_concurrent_streams,
+            };
+            let Some(conn) = (0..num_connections)
+                .map(|i| (start + i) % num_connections)
+                .find(|&ix| connections[ix].pending_messages.len() < max_in_flight)
+                .map(|ix| &mut connections[ix])
+            else {
+                return Some(message);
+            };
             conn.pending_messages.insert(message.message_id);
             self.pending_events.push_back(ToSwarm::NotifyHandler {
                 peer_id: message.peer_id,
                 handler: NotifyHandler::One(conn.id),
-                event: message,
+                event: HandlerIn::Send(message),
             });
             None
         } else {
@@ -107,6 +1140,30 @@ where
             ..
         }: ConnectionClosed,
     ) {
+        // Messages dispatched to the connection that are still waiting to be handed to its
+        // handler would be dropped by the swarm along with the notification
+        let undelivered = self.take_undelivered(peer_id, connection_id);
+        let requeued = if self.config.requeue_undelivered {
+            undelivered
+        } else {
+            Vec::new()
+        };
+        let requeued_ids = requeued
+            .iter()
+            .map(|message| message.message_id)
+            .collect::<HashSet<_>>();
+        if !requeued.is_empty() {
+            tracing::debug!(
+                "requeueing {} messages dispatched to closed connection {connection_id}",
+                requeued.len()
+            );
+            let pending = self.pending_outbound_messages.entry(peer_id).or_default();
+            // The messages were dispatched before any that are still queued
+            for (ix, message) in requeued.into_iter().enumerate() {
+                pending.insert(ix, message);
+            }
+        }
+
         let connections = self
             .connected
             .get_mut(&peer_id)
@@ -117,20 +1174,88 @@ where
             .position(|c| c.id == connection_id)
             .map(|p: usize| connections.remove(p))
             .expect("Expected connection to be established before closing.");
+        self.shared_handler_state.remove_connection(connection_id);
+
+        #[cfg(feature = "testing")]
+        self.handler_states.remove(&connection_id);
 
         debug_assert_eq!(connections.is_empty(), remaining_established == 0);
         if connections.is_empty() {
             self.connected.remove(&peer_id);
+            self.pending_events
+                .push_back(ToSwarm::GenerateEvent(Event::PeerDisconnected { peer_id }));
+            // Messages queued while all connections were saturated need a new connection
+            if self
+                .pending_outbound_messages
+                .get(&peer_id)
+                .is_some_and(|pending| !pending.is_empty())
+            {
+                self.dial(peer_id);
+            }
+        } else {
+            self.dispatch_queued_messages(&peer_id);
         }
 
-        for message_id in connection.pending_messages {
-            self.pending_events
-                .push_back(ToSwarm::GenerateEvent(Event::InboundFailure {
-                    peer_id,
-                    message_id,
-                    error: Error::ConnectionClosed,
-                }));
+        // Copies of redundant messages only fail the message once every copy has failed
+        let pending_messages = connection
+            .pending_messages
+            .into_iter()
+            .filter(|message_id| !requeued_ids.contains(message_id))
+            .filter(|message_id| self.on_redundant_outcome(peer_id, *message_id, false))
+            .collect::<Vec<_>>();
+        if self.config.coalesce_bulk_failures {
+            if !pending_messages.is_empty() {
+                let mut message_ids = pending_messages;
+                message_ids.sort_unstable();
+                self.pending_events
+                    .push_back(ToSwarm::GenerateEvent(Event::BulkFailure {
+                        peer_id,
+                        message_ids,
+                        remote_address: connection.remote_address,
+                        error: Error::ConnectionClosed,
+                    }));
+            }
+        } else {
+            for message_id in pending_messages {
+                self.pending_events
+                    .push_back(ToSwarm::GenerateEvent(Event::OutboundFailure {
+                        peer_id,
+                        message_id,
+                        error: Error::ConnectionClosed,
+                    }));
+            }
         }
+
+        self.check_flushed(&peer_id);
+    }
+
+    /// Removes the messages waiting in `pending_events` to be handed to the handler of the
+    /// connection and returns them, in the order they were dispatched. Copies of redundant messages
+    /// are dropped, since the message is still in flight on other connections.
+    fn take_undelivered(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+    ) -> Vec<OutboundMessage<TCodec::Message>> {
+        let mut undelivered = Vec::new();
+        for event in std::mem::take(&mut self.pending_events) {
+            match event {
+                ToSwarm::NotifyHandler {
+                    peer_id: event_peer_id,
+                    handler: NotifyHandler::One(event_connection_id),
+                    event: HandlerIn::Send(message),
+                } if event_peer_id == peer_id && event_connection_id == connection_id => {
+                    if !self
+                        .redundant_messages
+                        .contains_key(&(peer_id, message.message_id))
+                    {
+                        undelivered.push(message);
+                    }
+                }
+                event => self.pending_events.push_back(event),
+            }
+        }
+        undelivered
     }
 
     fn on_address_change(&mut self, address_change: AddressChange) {
@@ -140,6 +1265,7 @@ where
             new,
             ..
         } = address_change;
+        self.remember_address(peer_id, new);
         if let Some(connections) = self.connected.get_mut(&peer_id) {
             for connection in connections {
                 if connection.id == connection_id {
@@ -150,8 +1276,40 @@ where
         }
     }
 
+    /// Remembers the address of an outbound connection so that the peer can be dialed at it
+    /// later. Addresses of inbound connections are usually not dialable and are ignored.
+    fn remember_address(&mut self, peer_id: PeerId, endpoint: &ConnectedPoint) {
+        let ConnectedPoint::Dialer { address, .. } = endpoint else {
+            return;
+        };
+        if self.config.remembered_addresses == 0 {
+            return;
+        }
+        if self
+            .remembered_addresses
+            .insert(peer_id, address.clone())
+            .is_some()
+        {
+            self.remembered_address_order.retain(|p| *p != peer_id);
+        }
+        self.remembered_address_order.push_back(peer_id);
+        while self.remembered_address_order.len() > self.config.remembered_addresses {
+            if let Some(oldest) = self.remembered_address_order.pop_front() {
+                self.remembered_addresses.remove(&oldest);
+            }
+        }
+    }
+
     fn on_dial_failure(&mut self, DialFailure { peer_id, .. }: DialFailure) {
         if let Some(peer) = peer_id {
+            self.dialing_additional.remove(&peer);
+            self.dialing.remove(&peer);
+            self.dial_deadlines.remove(&peer);
+            // Messages queued for a connected peer are waiting for a saturated connection to
+            // free up and are unaffected by the failure of a concurrent dial.
+            if self.connected.contains_key(&peer) {
+                return;
+            }
             // If there are pending outgoing messages when a dial failure occurs,
             // it is implied that we are not connected to the peer, since pending
             // outgoing messages are drained when a connection is established and
@@ -168,26 +1326,292 @@ where
                         }));
                 }
             }
+            self.check_flushed(&peer);
+        }
+    }
+
+    /// Dials the peer, unless a dial issued by this method is already in progress.
+    fn dial(&mut self, peer_id: PeerId) {
+        if self.dialing.insert(peer_id) {
+            let opts = self.dial_opts(peer_id, PeerCondition::default());
+            self.pending_events.push_back(ToSwarm::Dial { opts });
+        }
+        self.set_dial_deadline(peer_id);
+    }
+
+    /// Dials another connection to a connected peer whose connections are all saturated.
+    fn dial_additional(&mut self, peer_id: PeerId) {
+        if self.dialing_additional.insert(peer_id) {
+            let opts = self.dial_opts(peer_id, PeerCondition::Always);
+            self.pending_events.push_back(ToSwarm::Dial { opts });
+        }
+        self.set_dial_deadline(peer_id);
+    }
+
+    /// Sets the deadline for the peer to be connected, if [`Config::dial_timeout`] is set and no
+    /// deadline is pending.
+    fn set_dial_deadline(&mut self, peer_id: PeerId) {
+        if let Some(dial_timeout) = self.config.dial_timeout {
+            self.dial_deadlines
+                .entry(peer_id)
+                .or_insert_with(|| Instant::now() + dial_timeout);
+            self.schedule_dial_timer();
         }
     }
 
+    /// Arms the dial timer for the earliest dial deadline, unless it is already armed for an
+    /// earlier one.
+    fn schedule_dial_timer(&mut self) {
+        let Some(next_at) = self.dial_deadlines.values().min().copied() else {
+            return;
+        };
+        if self
+            .dial_timer
+            .as_ref()
+            .is_some_and(|(at, _)| *at <= next_at)
+        {
+            return;
+        }
+        let delay = Delay::new(next_at.saturating_duration_since(Instant::now()));
+        self.dial_timer = Some((next_at, delay));
+    }
+
+    /// Fails the queued messages of peers that were not connected within
+    /// [`Config::dial_timeout`] of being dialed.
+    fn handle_pending_dial_timeout(&mut self, cx: &mut Context<'_>) {
+        while let Some((_, timer)) = self.dial_timer.as_mut() {
+            if timer.poll_unpin(cx).is_pending() {
+                return;
+            }
+            self.dial_timer = None;
+            let now = Instant::now();
+            let expired = self
+                .dial_deadlines
+                .iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(peer_id, _)| *peer_id)
+                .collect::<Vec<_>>();
+            for peer_id in expired {
+                self.dial_deadlines.remove(&peer_id);
+                // Messages for a connected peer wait for a saturated connection rather than the
+                // additional connection, so only the dial is given up on
+                if self.connected.contains_key(&peer_id) {
+                    self.dialing_additional.remove(&peer_id);
+                    continue;
+                }
+                // A dial that completes later is not awaited, so that new messages dial again
+                self.dialing.remove(&peer_id);
+                if let Some(pending) = self.pending_outbound_messages.remove(&peer_id) {
+                    tracing::debug!(
+                        "peer {peer_id} was not connected within the dial timeout, failing {} messages",
+                        pending.len()
+                    );
+                    for message in pending {
+                        self.pending_events.push_back(ToSwarm::GenerateEvent(
+                            Event::OutboundFailure {
+                                peer_id,
+                                message_id: message.message_id,
+                                error: Error::DialTimeout,
+                            },
+                        ));
+                    }
+                }
+                self.check_flushed(&peer_id);
+            }
+            self.schedule_dial_timer();
+        }
+    }
+
+    fn dial_opts(&self, peer_id: PeerId, condition: PeerCondition) -> DialOpts {
+        let mut addresses = Vec::new();
+        // The last dialed address is known to have worked, so it is tried first
+        if let Some(address) = self.remembered_addresses.get(&peer_id) {
+            addresses.push(address.clone());
+        }
+        for address in self.peer_addresses.get(&peer_id).into_iter().flatten() {
+            if !addresses.contains(address) {
+                addresses.push(address.clone());
+            }
+        }
+        let opts = DialOpts::peer_id(peer_id).condition(condition);
+        if addresses.is_empty() {
+            return opts.build();
+        }
+        opts.addresses(addresses)
+            .extend_addresses_through_behaviour()
+            .build()
+    }
+
+    fn new_handler(&mut self, peer_id: PeerId, connection_id: ConnectionId) -> Handler<TCodec> {
+        let mut handler = Handler::new(
+            peer_id,
+            connection_id,
+            self.protocols.clone(),
+            self.codec.clone(),
+            self.shared_handler_state.clone(),
+            &self.config,
+        );
+        #[cfg(feature = "testing")]
+        {
+            let slot = Arc::new(Mutex::new(handler.debug_state()));
+            handler.set_debug_state_slot(slot.clone());
+            self.handler_states.insert(connection_id, slot);
+        }
+        #[cfg(not(feature = "testing"))]
+        let _ = connection_id;
+        if self.is_shutting_down {
+            handler.shutdown();
+        }
+        if let Some(priority) = self.peer_priorities.get(&peer_id) {
+            handler.set_priority(*priority);
+        }
+        if let Some(filter) = &self.inbound_filter {
+            handler.set_inbound_filter(filter.clone());
+        }
+        handler
+    }
+
+    // Connections are only tracked once the swarm reports them as established, since another
+    // behaviour may still deny a connection after its handler has been created, in which case no
+    // ConnectionClosed event is ever emitted for it.
     fn on_connection_established(
         &mut self,
-        handler: &mut Handler<TCodec>,
-        peer_id: PeerId,
-        connection_id: ConnectionId,
-        remote_address: Option<Multiaddr>,
+        ConnectionEstablished {
+            peer_id,
+            connection_id,
+            endpoint,
+            ..
+        }: ConnectionEstablished,
     ) {
-        let mut connection = Connection::new(connection_id, remote_address);
+        let remote_address = Some(endpoint.get_remote_address().clone());
+        self.pending_events
+            .push_back(ToSwarm::GenerateEvent(Event::PeerConnected {
+                peer_id,
+                connection_id,
+                remote_address: remote_address.clone(),
+            }));
+        self.dialing_additional.remove(&peer_id);
+        self.dialing.remove(&peer_id);
+        self.dial_deadlines.remove(&peer_id);
+        self.remember_address(peer_id, endpoint);
+        self.connected
+            .entry(peer_id)
+            .or_default()
+            .push(Connection::new(connection_id, remote_address));
+        #[cfg(feature = "message-store")]
+        self.load_stored_messages(peer_id);
+        self.dispatch_queued_messages(&peer_id);
+    }
+
+    /// Moves the messages persisted for the peer into its pending queue, skipping those already
+    /// loaded. Messages that don't fit in the queue stay in the store until it has room.
+    #[cfg(feature = "message-store")]
+    fn load_stored_messages(&mut self, peer_id: PeerId) {
+        let Some(store) = self.message_store.as_mut() else {
+            return;
+        };
+        self.unloaded_stored_messages.remove(&peer_id);
+        let messages = match store.load(&peer_id) {
+            Ok(messages) => messages,
+            Err(err) => {
+                tracing::warn!("failed to load stored messages for peer {peer_id}: {err}");
+                return;
+            }
+        };
+        let pending = self.pending_outbound_messages.entry(peer_id).or_default();
+        for message in messages {
+            if self
+                .stored_messages
+                .contains(&(peer_id, message.message_id))
+            {
+                continue;
+            }
+            if pending.len() >= self.config.max_pending_outbound_per_peer {
+                self.unloaded_stored_messages.insert(peer_id);
+                break;
+            }
+            self.stored_messages.insert((peer_id, message.message_id));
+            match priority_position(pending.iter(), message.priority) {
+                Some(ix) => pending.insert(ix, message),
+                None => pending.push(message),
+            }
+        }
+    }
 
-        if let Some(pending_messages) = self.pending_outbound_messages.remove(&peer_id) {
-            for message in pending_messages {
-                connection.pending_messages.insert(message.message_id);
-                handler.on_behaviour_event(message);
+    /// Removes stored messages from the store once sent, cancelled or failed for good. Messages
+    /// that failed with a retryable error are kept in the store and loaded again on the next
+    /// connection to the peer.
+    #[cfg(feature = "message-store")]
+    fn update_stored_messages(&mut self, event: &Event<TCodec::Message>) {
+        if self.stored_messages.is_empty() {
+            return;
+        }
+        match event {
+            Event::MessageSent {
+                peer_id,
+                message_id,
+                ..
+            }
+            | Event::MessageAcked {
+                peer_id,
+                message_id,
+                ..
             }
+            | Event::MessageCancelled {
+                peer_id,
+                message_id,
+            } => self.remove_stored_message(peer_id, *message_id),
+            Event::OutboundFailure {
+                peer_id,
+                message_id,
+                error,
+            } => self.on_stored_message_failed(peer_id, *message_id, error),
+            Event::BulkFailure {
+                peer_id,
+                message_ids,
+                error,
+                ..
+            } => {
+                for message_id in message_ids {
+                    self.on_stored_message_failed(peer_id, *message_id, error);
+                }
+            }
+            _ => {}
         }
+    }
+
+    #[cfg(feature = "message-store")]
+    fn on_stored_message_failed(&mut self, peer_id: &PeerId, message_id: MessageId, error: &Error) {
+        if error.is_retryable() {
+            self.stored_messages.remove(&(*peer_id, message_id));
+        } else {
+            self.remove_stored_message(peer_id, message_id);
+        }
+    }
 
-        self.connected.entry(peer_id).or_default().push(connection);
+    #[cfg(feature = "message-store")]
+    fn remove_stored_message(&mut self, peer_id: &PeerId, message_id: MessageId) {
+        if !self.stored_messages.remove(&(*peer_id, message_id)) {
+            return;
+        }
+        if let Some(store) = self.message_store.as_mut() {
+            if let Err(err) = store.remove(peer_id, message_id) {
+                tracing::warn!(
+                    "failed to remove stored message {message_id} for peer {peer_id}: {err}"
+                );
+            }
+        }
+    }
+
+    fn get_connection_mut(
+        &mut self,
+        peer_id: &PeerId,
+        connection_id: ConnectionId,
+    ) -> Option<&mut Connection> {
+        self.connected
+            .get_mut(peer_id)?
+            .iter_mut()
+            .find(|c| c.id == connection_id)
     }
 }
 
@@ -203,39 +1627,26 @@ where
         connection_id: ConnectionId,
         peer: PeerId,
         _local_addr: &Multiaddr,
-        remote_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
     ) -> Result<THandler<Self>, ConnectionDenied> {
-        let mut handler = Handler::<TCodec>::new(peer, self.protocol.clone(), &self.config);
-        self.on_connection_established(
-            &mut handler,
-            peer,
-            connection_id,
-            Some(remote_addr.clone()),
-        );
-
-        Ok(handler)
+        Ok(self.new_handler(peer, connection_id))
     }
 
     fn handle_established_outbound_connection(
         &mut self,
         connection_id: ConnectionId,
         peer: PeerId,
-        remote_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
         _role_override: Endpoint,
     ) -> Result<THandler<Self>, ConnectionDenied> {
-        let mut handler = Handler::new(peer, self.protocol.clone(), &self.config);
-        self.on_connection_established(
-            &mut handler,
-            peer,
-            connection_id,
-            Some(remote_addr.clone()),
-        );
-        Ok(handler)
+        Ok(self.new_handler(peer, connection_id))
     }
 
     fn on_swarm_event(&mut self, event: FromSwarm) {
         match event {
-            FromSwarm::ConnectionEstablished(_) => {}
+            FromSwarm::ConnectionEstablished(connection_established) => {
+                self.on_connection_established(connection_established)
+            }
             FromSwarm::ConnectionClosed(connection_closed) => {
                 self.on_connection_closed(connection_closed)
             }
@@ -246,20 +1657,111 @@ where
     }
     fn on_connection_handler_event(
         &mut self,
-        _peer_id: PeerId,
-        _connection_id: ConnectionId,
-        event: THandlerOutEvent<Self>,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        mut event: THandlerOutEvent<Self>,
     ) {
-        self.pending_events.push_back(ToSwarm::GenerateEvent(event));
+        match &mut event {
+            Event::ReceivedMessage { remote_address, .. }
+            | Event::InboundFailure { remote_address, .. } => {
+                *remote_address = self
+                    .get_connection_mut(&peer_id, connection_id)
+                    .and_then(|conn| conn.remote_address.clone());
+            }
+            Event::MessageSent { message_id, .. }
+            | Event::MessageAcked { message_id, .. }
+            | Event::Response {
+                request_id: message_id,
+                ..
+            } => {
+                if let Some(conn) = self.get_connection_mut(&peer_id, connection_id) {
+                    conn.pending_messages.remove(message_id);
+                    // Batched messages are counted individually, since the handler reports them
+                    // separately
+                    conn.messages_sent += 1;
+                }
+            }
+            Event::OutboundFailure { message_id, .. } => {
+                if let Some(conn) = self.get_connection_mut(&peer_id, connection_id) {
+                    conn.pending_messages.remove(message_id);
+                }
+            }
+            _ => {}
+        }
+        self.dispatch_queued_messages(&peer_id);
+        let should_emit = match &event {
+            Event::MessageSent { message_id, .. } | Event::MessageAcked { message_id, .. } => {
+                self.on_redundant_outcome(peer_id, *message_id, true)
+            }
+            Event::OutboundFailure { message_id, .. } => {
+                self.on_redundant_outcome(peer_id, *message_id, false)
+            }
+            _ => true,
+        };
+        if should_emit {
+            self.pending_events.push_back(ToSwarm::GenerateEvent(event));
+        }
+        self.check_flushed(&peer_id);
     }
 
     fn poll(
         &mut self,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
-        if let Some(event) = self.pending_events.pop_front() {
+        self.handle_pending_dial_timeout(cx);
+        while let Some(event) = self.pending_events.pop_front() {
+            if let ToSwarm::GenerateEvent(event) = &event {
+                self.update_retained_payloads(event);
+                #[cfg(feature = "message-store")]
+                self.update_stored_messages(event);
+            }
+            let event = match event {
+                ToSwarm::GenerateEvent(event) => match self.complete_awaited_message(event) {
+                    Some(event) => ToSwarm::GenerateEvent(event),
+                    None => continue,
+                },
+                event => event,
+            };
+            let is_app_event = matches!(event, ToSwarm::GenerateEvent(_));
+            if let Some(sender) = self.event_sender.as_mut().filter(|_| is_app_event) {
+                match sender.poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {
+                        let ToSwarm::GenerateEvent(app_event) = event else {
+                            unreachable!("checked above")
+                        };
+                        #[cfg(feature = "metrics")]
+                        if let Some(metrics) = &self.config.metrics {
+                            metrics.record(&app_event);
+                        }
+                        match sender.try_send(app_event) {
+                            Ok(()) => continue,
+                            // The stream was dropped, so the event is emitted to the swarm
+                            Err(err) => {
+                                self.event_sender = None;
+                                return Poll::Ready(ToSwarm::GenerateEvent(err.into_inner()));
+                            }
+                        }
+                    }
+                    // The stream was dropped, so events are emitted to the swarm again
+                    Poll::Ready(Err(_)) => {
+                        self.event_sender = None;
+                    }
+                    // The stream is full. Wait for capacity rather than drop or reorder events.
+                    Poll::Pending => {
+                        self.pending_events.push_front(event);
+                        return Poll::Pending;
+                    }
+                }
+            }
+
+            #[cfg(feature = "metrics")]
+            if let (Some(metrics), ToSwarm::GenerateEvent(event)) = (&self.config.metrics, &event) {
+                metrics.record(event);
+            }
             return Poll::Ready(event);
-        } else if self.pending_events.capacity() > EMPTY_QUEUE_SHRINK_THRESHOLD {
+        }
+
+        if self.pending_events.capacity() > EMPTY_QUEUE_SHRINK_THRESHOLD {
             self.pending_events.shrink_to_fit();
         }
 
@@ -267,12 +1769,73 @@ where
     }
 }
 
+/// Orders protocols so that those in `preference` come first, in the order they appear there.
+fn order_by_preference(
+    protocols: Vec<StreamProtocol>,
+    preference: &[StreamProtocol],
+    prefer_highest_version: bool,
+) -> Vec<StreamProtocol> {
+    let mut ordered = preference
+        .iter()
+        .filter(|p| protocols.contains(p))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut rest = protocols
+        .into_iter()
+        .filter(|p| !preference.contains(p))
+        .collect::<Vec<_>>();
+    if prefer_highest_version {
+        // Descending, so protocols without a version sort last
+        rest.sort_by_key(|p| std::cmp::Reverse(protocol_version(p)));
+    }
+    ordered.extend(rest);
+    ordered
+}
+
+/// Parses the dotted numeric version in the last path segment of the protocol, such as `1.5.0`
+/// in `/app/1.5.0`.
+fn protocol_version(protocol: &StreamProtocol) -> Option<Vec<u64>> {
+    let (_, version) = protocol.as_ref().rsplit_once('/')?;
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Options for [`Behaviour::send_message_inner`] that differ between the ways of sending a message.
+#[derive(Debug)]
+struct SendOptions {
+    protocol: Option<StreamProtocol>,
+    is_request: bool,
+    dedup_key: Option<u64>,
+    priority: u8,
+    ttl: Option<Duration>,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            protocol: None,
+            is_request: false,
+            dedup_key: None,
+            priority: DEFAULT_PRIORITY,
+            ttl: None,
+        }
+    }
+}
+
+/// Tracks the copies of a message sent with [`Behaviour::send_message_redundant`].
+#[derive(Debug)]
+struct RedundantDelivery {
+    /// The number of copies that have not yet been sent or failed.
+    remaining: usize,
+    is_delivered: bool,
+}
+
 /// Internal information tracked for an established connection.
 #[derive(Debug)]
 struct Connection {
     id: ConnectionId,
     remote_address: Option<Multiaddr>,
     pending_messages: HashSet<MessageId>,
+    messages_sent: u64,
 }
 
 impl Connection {
@@ -281,6 +1844,16 @@ impl Connection {
             id,
             remote_address,
             pending_messages: HashSet::new(),
+            messages_sent: 0,
+        }
+    }
+
+    fn stats(&self, shared_handler_state: &SharedState) -> ConnectionStats {
+        ConnectionStats {
+            connection_id: self.id,
+            num_pending: self.pending_messages.len(),
+            messages_sent: self.messages_sent,
+            substreams_opened: shared_handler_state.substreams_opened(self.id),
         }
     }
 }
@@ -0,0 +1,143 @@
+use crate::codec::{Codec, Framing, LengthPrefix, MessageTooLargeError};
+use ::bytes::{Bytes, BytesMut};
+use async_trait::async_trait;
+use libp2p::futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io;
+
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// A length-prefixed codec for raw [`Bytes`] messages. Messages are written directly to the stream
+/// without being copied into an intermediate buffer.
+#[derive(Debug, Clone, Default)]
+pub struct BytesCodec;
+
+#[async_trait]
+impl Codec for BytesCodec {
+    type Message = Bytes;
+
+    async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut len_buf = [0u8; 4];
+        reader.read_exact(&mut len_buf).await?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(MessageTooLargeError {
+                len,
+                limit: MAX_MESSAGE_SIZE,
+            }
+            .into());
+        }
+        let mut buf = BytesMut::zeroed(len);
+        reader.read_exact(&mut buf).await?;
+        Ok(buf.freeze())
+    }
+
+    fn framing(&self) -> Framing {
+        Framing {
+            prefix: LengthPrefix::U32BigEndian,
+            max_message_size: Some(MAX_MESSAGE_SIZE),
+        }
+    }
+
+    fn estimate_size(&self, message: &Self::Message) -> Option<usize> {
+        Some(4 + message.len())
+    }
+
+    async fn encode_to_bytes(&mut self, message: Self::Message) -> io::Result<Bytes> {
+        let len = message.len();
+        if len > MAX_MESSAGE_SIZE {
+            return Err(MessageTooLargeError {
+                len,
+                limit: MAX_MESSAGE_SIZE,
+            }
+            .into());
+        }
+        Ok(message)
+    }
+
+    fn prefers_bytes(&self) -> bool {
+        true
+    }
+
+    async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let len = message.len();
+        if len > MAX_MESSAGE_SIZE {
+            return Err(MessageTooLargeError {
+                len,
+                limit: MAX_MESSAGE_SIZE,
+            }
+            .into());
+        }
+        writer.write_all(&(len as u32).to_be_bytes()).await?;
+        writer.write_all(&message).await?;
+        Ok(())
+    }
+}
+
+/// A codec for raw [`Bytes`] messages that are delimited by the end of the stream instead of a
+/// length prefix, for interop with protocols that signal the end of a message by closing the
+/// stream. The writer closes the stream after writing the message and the reader reads until the
+/// end of the stream.
+///
+/// Since the stream can't be used after the message, this codec can't be used with
+/// [`Config::reuse_inbound_stream`](crate::Config::reuse_inbound_stream), and requests can only be
+/// answered if the transport supports half-closing streams.
+#[derive(Debug, Clone, Default)]
+pub struct EofBytesCodec;
+
+#[async_trait]
+impl Codec for EofBytesCodec {
+    type Message = Bytes;
+
+    async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        // Read at most one byte past the limit to detect oversized messages
+        reader
+            .take(MAX_MESSAGE_SIZE as u64 + 1)
+            .read_to_end(&mut buf)
+            .await?;
+        if buf.len() > MAX_MESSAGE_SIZE {
+            return Err(MessageTooLargeError {
+                len: buf.len(),
+                limit: MAX_MESSAGE_SIZE,
+            }
+            .into());
+        }
+        Ok(buf.into())
+    }
+
+    fn framing(&self) -> Framing {
+        Framing {
+            prefix: LengthPrefix::Eof,
+            max_message_size: Some(MAX_MESSAGE_SIZE),
+        }
+    }
+
+    fn estimate_size(&self, message: &Self::Message) -> Option<usize> {
+        Some(message.len())
+    }
+
+    async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let len = message.len();
+        if len > MAX_MESSAGE_SIZE {
+            return Err(MessageTooLargeError {
+                len,
+                limit: MAX_MESSAGE_SIZE,
+            }
+            .into());
+        }
+        writer.write_all(&message).await?;
+        writer.close().await
+    }
+}
@@ -0,0 +1,157 @@
+use crate::codec::LengthPrefix;
+use libp2p::futures::{AsyncRead, AsyncWrite};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+/// The number of most recently read bytes kept for [`DecodeDiagnostics::snippet`].
+const SNIPPET_LEN: usize = 32;
+
+/// The number of leading bytes kept to parse [`DecodeDiagnostics::frame_len`], enough for the
+/// longest varint length prefix.
+const HEADER_LEN: usize = 10;
+
+/// Context about where a [`Codec`](crate::Codec) failed to decode a message, available if
+/// [`Config::decode_diagnostics`](crate::Config::decode_diagnostics) is enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeDiagnostics {
+    /// The length of the frame announced by the message's length prefix, if the codec frames
+    /// messages with a [`LengthPrefix::U32BigEndian`] or [`LengthPrefix::UnsignedVarint`] prefix
+    /// and the prefix was read in full.
+    pub frame_len: Option<usize>,
+    /// The number of bytes the codec read from the stream before failing.
+    pub bytes_read: usize,
+    /// The last bytes read before the failure, up to 32 bytes.
+    pub snippet: Vec<u8>,
+}
+
+impl fmt::Display for DecodeDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed after reading {} bytes", self.bytes_read)?;
+        if let Some(frame_len) = self.frame_len {
+            write!(f, " of a {frame_len} byte frame")?;
+        }
+        write!(f, ", last bytes: ")?;
+        for byte in &self.snippet {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Wraps a reader to record how many bytes were read and, optionally, the first and the most
+/// recent bytes.
+pub(crate) struct DiagnosticReader<'a, R> {
+    inner: &'a mut R,
+    bytes_read: usize,
+    header: Vec<u8>,
+    snippet: Option<VecDeque<u8>>,
+}
+
+impl<'a, R> DiagnosticReader<'a, R> {
+    pub fn new(inner: &'a mut R, record_snippet: bool) -> Self {
+        Self {
+            inner,
+            bytes_read: 0,
+            header: Vec::new(),
+            snippet: record_snippet.then(|| VecDeque::with_capacity(SNIPPET_LEN)),
+        }
+    }
+
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+
+    /// Builds the diagnostics, parsing the frame length from the bytes read according to `prefix`.
+    pub fn into_diagnostics(self, prefix: LengthPrefix) -> DecodeDiagnostics {
+        let frame_len = match prefix {
+            LengthPrefix::U32BigEndian => self
+                .header
+                .get(..4)
+                .map(|len| u32::from_be_bytes(len.try_into().expect("4 bytes")) as usize),
+            LengthPrefix::UnsignedVarint => decode_varint(&self.header),
+            _ => None,
+        };
+        DecodeDiagnostics {
+            frame_len,
+            bytes_read: self.bytes_read,
+            snippet: self.snippet.map(Vec::from).unwrap_or_default(),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DiagnosticReader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut *this.inner).poll_read(cx, buf))?;
+        this.bytes_read += n;
+        if let Some(snippet) = &mut this.snippet {
+            let read = &buf[..n];
+            let missing = HEADER_LEN.saturating_sub(this.header.len());
+            this.header.extend(&read[..missing.min(n)]);
+            let keep = &read[read.len().saturating_sub(SNIPPET_LEN)..];
+            let overflow = (snippet.len() + keep.len()).saturating_sub(SNIPPET_LEN);
+            snippet.drain(..overflow);
+            snippet.extend(keep);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Decodes an unsigned LEB128 varint from the start of `bytes`, if it is complete.
+fn decode_varint(bytes: &[u8]) -> Option<usize> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(HEADER_LEN) {
+        value |= u64::from(byte & 0x7f).checked_shl(7 * i as u32)?;
+        if byte & 0x80 == 0 {
+            return usize::try_from(value).ok();
+        }
+    }
+    None
+}
+
+/// Wraps a writer to record how many bytes were written.
+pub(crate) struct CountingWriter<'a, W> {
+    inner: &'a mut W,
+    bytes_written: usize,
+}
+
+impl<'a, W> CountingWriter<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        Self {
+            inner,
+            bytes_written: 0,
+        }
+    }
+
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut *this.inner).poll_write(cx, buf))?;
+        this.bytes_written += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_close(cx)
+    }
+}
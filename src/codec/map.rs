@@ -0,0 +1,178 @@
+use crate::codec::{Codec, Framing};
+use ::bytes::Bytes;
+use async_trait::async_trait;
+use libp2p::futures::{AsyncRead, AsyncWrite};
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+
+/// A codec that exchanges messages of type `M` using the wire format of an inner codec `C`.
+///
+/// Created with [`MapCodec::new`], outbound messages are converted into the inner codec's message
+/// type with [`From`] before encoding, and inbound messages are converted back with [`TryFrom`]
+/// after decoding. A failed conversion is returned as an [`io::ErrorKind::InvalidData`] decode
+/// error. This allows several message types to share a wire format, or a behaviour to use an
+/// application type without changing the codec.
+///
+/// Created with [`MapCodec::from_fns`], messages are converted with a pair of closures instead,
+/// which also maps types the conversion traits can't be implemented for. Such a codec has no
+/// [`Default`], so the behaviour is created with
+/// [`Behaviour::with_codec`](crate::Behaviour::with_codec).
+pub struct MapCodec<C, M, F = MapInto> {
+    inner: C,
+    map: F,
+    _message: PhantomData<fn() -> M>,
+}
+
+/// Converts the messages of a [`MapCodec`] with [`From`] and [`TryFrom`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MapInto;
+
+/// Converts the messages of a [`MapCodec`] with an encode and a decode closure.
+#[derive(Clone)]
+pub struct MapFns<E, D> {
+    encode: E,
+    decode: D,
+}
+
+impl<C, M> MapCodec<C, M> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            map: MapInto,
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<C, M, E, D> MapCodec<C, M, MapFns<E, D>>
+where
+    C: Codec,
+    E: Fn(&M) -> C::Message,
+    D: Fn(C::Message) -> io::Result<M>,
+{
+    /// Creates a codec that converts outbound messages into the inner codec's message type with
+    /// `encode`, and inbound messages back with `decode`.
+    ///
+    /// `encode` borrows the message, so that it can also be used to forward
+    /// [`Codec::estimate_size`] to the inner codec.
+    pub fn from_fns(inner: C, encode: E, decode: D) -> Self {
+        Self {
+            inner,
+            map: MapFns { encode, decode },
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<C, M, F> MapCodec<C, M, F> {
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<C, M> Codec for MapCodec<C, M>
+where
+    C: Codec + Send,
+    C::Message: From<M>,
+    M: TryFrom<C::Message> + fmt::Debug + Send,
+    <M as TryFrom<C::Message>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Message = M;
+
+    async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let message = self.inner.decode_from(reader).await?;
+        M::try_from(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        self.inner.encode_to(writer, message.into()).await
+    }
+
+    fn framing(&self) -> Framing {
+        self.inner.framing()
+    }
+
+    async fn encode_to_bytes(&mut self, message: Self::Message) -> io::Result<Bytes> {
+        self.inner.encode_to_bytes(message.into()).await
+    }
+
+    fn prefers_bytes(&self) -> bool {
+        self.inner.prefers_bytes()
+    }
+}
+
+#[async_trait]
+impl<C, M, E, D> Codec for MapCodec<C, M, MapFns<E, D>>
+where
+    C: Codec + Send,
+    M: fmt::Debug + Send,
+    E: Fn(&M) -> C::Message + Send,
+    D: Fn(C::Message) -> io::Result<M> + Send,
+{
+    type Message = M;
+
+    async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let message = self.inner.decode_from(reader).await?;
+        (self.map.decode)(message)
+    }
+
+    async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let message = (self.map.encode)(&message);
+        self.inner.encode_to(writer, message).await
+    }
+
+    fn estimate_size(&self, message: &Self::Message) -> Option<usize> {
+        self.inner.estimate_size(&(self.map.encode)(message))
+    }
+
+    fn framing(&self) -> Framing {
+        self.inner.framing()
+    }
+
+    async fn encode_to_bytes(&mut self, message: Self::Message) -> io::Result<Bytes> {
+        let message = (self.map.encode)(&message);
+        self.inner.encode_to_bytes(message).await
+    }
+
+    fn prefers_bytes(&self) -> bool {
+        self.inner.prefers_bytes()
+    }
+}
+
+impl<C: Default, M> Default for MapCodec<C, M> {
+    fn default() -> Self {
+        Self::new(C::default())
+    }
+}
+
+impl<C: Clone, M, F: Clone> Clone for MapCodec<C, M, F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            map: self.map.clone(),
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<C: fmt::Debug, M, F> fmt::Debug for MapCodec<C, M, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MapCodec")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
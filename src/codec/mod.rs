@@ -1,14 +1,32 @@
+#[cfg(feature = "bytes")]
+pub mod bytes;
+mod diagnostics;
+mod map;
 #[cfg(feature = "prost")]
 pub mod prost;
 
+pub use diagnostics::DecodeDiagnostics;
+pub(crate) use diagnostics::{CountingWriter, DiagnosticReader};
+pub use map::{MapCodec, MapFns, MapInto};
+
+use ::bytes::Bytes;
 use libp2p::futures::{AsyncRead, AsyncWrite};
 use std::{fmt, io};
 
 /// A `Codec` defines the request and response types
 /// for a request-response [`Behaviour`](crate::Behaviour) protocol or
 /// protocol family and how they are encoded / decoded on an I/O stream.
+///
+/// The handler clones its codec for every inbound and outbound stream, since streams are read and
+/// written concurrently, so cloning should be cheap. A codec with expensive state, such as a
+/// compression dictionary or a buffer pool, should keep that state behind an
+/// [`Arc`](std::sync::Arc) so that clones share it.
+///
+/// A codec that implements [`Default`] can be used with [`Behaviour::new`](crate::Behaviour::new),
+/// otherwise the behaviour is created with
+/// [`Behaviour::with_codec`](crate::Behaviour::with_codec).
 #[async_trait::async_trait]
-pub trait Codec: Default {
+pub trait Codec {
     /// The type of inbound and outbound message.
     type Message: fmt::Debug + Send;
 
@@ -23,4 +41,129 @@ pub trait Codec: Default {
     async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
     where
         W: AsyncWrite + Unpin + Send;
+
+    /// Returns the number of bytes the message will occupy on the wire, if it can be estimated
+    /// without encoding it. Used to reject messages larger than
+    /// [`Config::max_message_size`](crate::Config::max_message_size) before they are queued.
+    fn estimate_size(&self, _message: &Self::Message) -> Option<usize> {
+        None
+    }
+
+    /// Describes how the codec frames messages on the wire, so that a mismatch with the framing
+    /// used by a remote implementation can be detected. Defaults to [`Framing::UNKNOWN`].
+    fn framing(&self) -> Framing {
+        Framing::UNKNOWN
+    }
+
+    /// Encodes a message into a single buffer, without its length prefix. When
+    /// [`Codec::prefers_bytes`] returns true the handler writes this buffer after a
+    /// [`LengthPrefix::U32BigEndian`] prefix instead of calling [`Codec::encode_to`], so a codec
+    /// whose messages already are buffers can hand them to the stream without copying them.
+    ///
+    /// The default encodes the message with [`Codec::encode_to`] into a new buffer and strips the
+    /// prefix given by [`Codec::framing`].
+    async fn encode_to_bytes(&mut self, message: Self::Message) -> io::Result<Bytes>
+    where
+        Self: Send,
+    {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf, message).await?;
+        let prefix_len = match self.framing().prefix {
+            LengthPrefix::U32BigEndian => 4.min(buf.len()),
+            _ => 0,
+        };
+        Ok(Bytes::from(buf).slice(prefix_len..))
+    }
+
+    /// Whether the handler should encode messages with [`Codec::encode_to_bytes`] rather than
+    /// [`Codec::encode_to`]. Only codecs framed with a [`LengthPrefix::U32BigEndian`] prefix may
+    /// return true. Defaults to false.
+    fn prefers_bytes(&self) -> bool {
+        false
+    }
+}
+
+/// The wire framing of a [`Codec`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    /// The length prefix written before each message.
+    pub prefix: LengthPrefix,
+    /// The maximum length of a message in bytes, excluding the prefix, if limited.
+    pub max_message_size: Option<usize>,
+}
+
+impl Framing {
+    /// The framing of a codec that doesn't describe it.
+    pub const UNKNOWN: Self = Self {
+        prefix: LengthPrefix::Unknown,
+        max_message_size: None,
+    };
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max_message_size {
+            Some(max) => write!(f, "{} length prefix, at most {max} bytes", self.prefix),
+            None => write!(f, "{} length prefix, unlimited", self.prefix),
+        }
+    }
+}
+
+/// The length prefix written before each message by a [`Codec`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthPrefix {
+    /// The framing is not known.
+    Unknown,
+    /// Messages are not length prefixed because the message format is self-delimiting.
+    SelfDelimiting,
+    /// A 4 byte big endian unsigned integer.
+    U32BigEndian,
+    /// An unsigned LEB128 varint, as used by `unsigned-varint` and protobuf.
+    UnsignedVarint,
+    /// Messages are not length prefixed. The sender closes its write half of the stream after
+    /// the message and the receiver reads until the end of the stream, so only one message can be
+    /// sent per stream.
+    Eof,
+}
+
+impl fmt::Display for LengthPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "unknown"),
+            Self::SelfDelimiting => write!(f, "no"),
+            Self::U32BigEndian => write!(f, "u32 big endian"),
+            Self::UnsignedVarint => write!(f, "unsigned varint"),
+            Self::Eof => write!(f, "no (end of stream)"),
+        }
+    }
+}
+
+/// Returned by a [`Codec`] as the inner error of an [`io::Error`] when a message exceeds the
+/// codec's size limit. The handler reports it as
+/// [`Error::MessageTooLarge`](crate::error::Error::MessageTooLarge) rather than a generic codec
+/// error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTooLargeError {
+    /// The length of the message in bytes.
+    pub len: usize,
+    /// The maximum length of a message in bytes.
+    pub limit: usize,
+}
+
+impl fmt::Display for MessageTooLargeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "message of {} bytes exceeds the limit of {} bytes",
+            self.len, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MessageTooLargeError {}
+
+impl From<MessageTooLargeError> for io::Error {
+    fn from(err: MessageTooLargeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
 }
@@ -1,4 +1,4 @@
-use crate::codec::Codec;
+use crate::codec::{Codec, Framing, LengthPrefix, MessageTooLargeError};
 use async_trait::async_trait;
 use libp2p::futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use std::fmt;
@@ -20,7 +20,7 @@ impl<TMsg> Default for ProstCodec<TMsg> {
 #[async_trait]
 impl<TMsg> Codec for ProstCodec<TMsg>
 where
-    TMsg: prost::Message + Default,
+    TMsg: prost::Message + Default + std::fmt::Debug,
 {
     type Message = TMsg;
 
@@ -32,40 +32,48 @@ where
         reader.read_exact(&mut len_buf).await?;
         let len = u32::from_be_bytes(len_buf) as usize;
         if len > MAX_MESSAGE_SIZE {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "message too large",
-            ));
+            return Err(MessageTooLargeError {
+                len,
+                limit: MAX_MESSAGE_SIZE,
+            }
+            .into());
         }
         let mut buf = vec![0u8; len];
         reader.read_exact(&mut buf).await?;
         let mut slice = &buf[..];
-        let message = prost::Message::decode(&mut slice)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let message = prost::Message::decode(&mut slice).map_err(std::io::Error::other)?;
 
         if !slice.is_empty() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "bytes remaining on buffer",
-            ));
+            return Err(std::io::Error::other("bytes remaining on buffer"));
         }
         Ok(message)
     }
 
+    fn framing(&self) -> Framing {
+        Framing {
+            prefix: LengthPrefix::U32BigEndian,
+            max_message_size: Some(MAX_MESSAGE_SIZE),
+        }
+    }
+
+    fn estimate_size(&self, message: &Self::Message) -> Option<usize> {
+        // The length prefix followed by the encoded message
+        Some(4 + message.encoded_len())
+    }
+
     async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> std::io::Result<()>
     where
         W: AsyncWrite + Unpin + Send,
     {
         let mut buf = Vec::new();
-        message
-            .encode(&mut buf)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        message.encode(&mut buf).map_err(std::io::Error::other)?;
         let len = buf.len();
         if len > MAX_MESSAGE_SIZE {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "message too large",
-            ));
+            return Err(MessageTooLargeError {
+                len,
+                limit: MAX_MESSAGE_SIZE,
+            }
+            .into());
         }
         writer.write_all(&(len as u32).to_be_bytes()).await?;
         writer.write_all(&buf).await?;
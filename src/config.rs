@@ -1,16 +1,532 @@
+use crate::error::ConfigError;
+use crate::MessageId;
+#[cfg(feature = "metrics")]
+use crate::Metrics;
+use libp2p::StreamProtocol;
 use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub max_concurrent_streams: usize,
+    /// The maximum number of concurrent inbound and outbound streams across all connections, or
+    /// `None` for no limit. Streams beyond this limit are dropped, failing outbound messages with
+    /// [`Error::AtCapacity`](crate::error::Error::AtCapacity).
+    pub max_total_concurrent_streams: Option<usize>,
+    /// The maximum number of concurrent inbound streams from a single peer across all of its
+    /// connections, or `None` for no limit. Inbound streams beyond this limit are dropped, each
+    /// reported as an [`Event::InboundFailure`](crate::Event::InboundFailure) with
+    /// [`Error::RateLimited`](crate::error::Error::RateLimited).
+    pub max_inbound_streams_per_peer: Option<usize>,
+    /// The time allowed for each substream once its protocol is negotiated. This covers writing an
+    /// outbound message along with waiting for its acknowledgement or response, and reading an
+    /// inbound message along with sending the response to a request. Each substream is timed
+    /// independently from when it is negotiated, so a slow substream doesn't affect others on the
+    /// same connection. An outbound message that times out fails with
+    /// [`Error::Timeout`](crate::error::Error::Timeout) for its message id, and an inbound one is
+    /// reported as an [`Event::InboundFailure`](crate::Event::InboundFailure).
+    ///
+    /// Changes with [`Behaviour::set_config`](crate::Behaviour::set_config) apply to a connection
+    /// once it has no substreams in progress.
     pub send_recv_timeout: Duration,
+    /// The timeout for negotiating the protocol of a substream, separate from
+    /// `send_recv_timeout`, which only starts once the substream is negotiated.
+    pub substream_timeout: Duration,
+    /// How long an idle connection is kept alive before the swarm's idle connection timeout
+    /// starts, scaled by the peer's [`ConnectionPriority`]: low priority connections get no grace,
+    /// normal ones this grace, and high priority ones four times it.
+    pub idle_connection_grace: Duration,
+    /// The maximum number of messages buffered for a peer that is not yet connected. Messages sent
+    /// beyond this limit are rejected with [`Error::QueueFull`](crate::error::Error::QueueFull).
+    pub max_pending_outbound_per_peer: usize,
+    /// The maximum size of an outbound message, or `None` for no limit. Messages whose size the
+    /// codec can [estimate](crate::Codec::estimate_size) as larger than this fail immediately with
+    /// [`Error::MessageTooLarge`](crate::error::Error::MessageTooLarge).
+    pub max_message_size: Option<usize>,
+    /// How inbound messages are handled once [`Behaviour::shutdown`](crate::Behaviour::shutdown)
+    /// has been called.
+    pub inbound_during_shutdown: InboundShutdownPolicy,
+    /// Whether outbound message ids are allocated from a single global sequence or a sequence per
+    /// peer.
+    pub id_scope: IdScope,
+    /// The first message id allocated with [`IdScope::Global`], so that ids from different nodes can
+    /// be told apart in aggregated logs. Per-peer ids always start at 0.
+    pub initial_message_id: MessageId,
+    /// Prefixes each outbound message with a sequence number, counted per connection as messages
+    /// are written to their substreams, and emits [`Event::SequenceGap`](crate::Event::SequenceGap)
+    /// when inbound sequence numbers are skipped. Since messages on concurrent substreams may
+    /// arrive out of order, a missing sequence number is only reported once
+    /// `max_concurrent_streams` later ones have been received. Must be enabled on both peers,
+    /// since it changes the wire format.
+    pub sequence_gap_detection: bool,
+    /// Waits for the receiver to acknowledge that it decoded each outbound message, emitting
+    /// [`Event::MessageAcked`](crate::Event::MessageAcked) instead of
+    /// [`Event::MessageSent`](crate::Event::MessageSent). Requests are acknowledged by their
+    /// response. Must be enabled on both peers, since it changes the wire format.
+    pub require_ack: bool,
+    /// Emits a single [`Event::BulkFailure`](crate::Event::BulkFailure) for the messages pending
+    /// on a closed connection, rather than an event per message.
+    pub coalesce_bulk_failures: bool,
+    /// Requeues messages dispatched to a connection that closed before the message was handed to
+    /// its handler, instead of failing them with
+    /// [`Error::ConnectionClosed`](crate::error::Error::ConnectionClosed). They are sent on another
+    /// connection to the peer, or the peer is dialed again. Messages the handler received are
+    /// always failed, since they may have been partly sent.
+    pub requeue_undelivered: bool,
+    /// Records the bytes read while decoding so that decode failures are reported as
+    /// [`Error::CodecError`](crate::error::Error::CodecError) with
+    /// [`DecodeDiagnostics`](crate::DecodeDiagnostics).
+    pub decode_diagnostics: bool,
+    /// Keeps reading messages from an inbound stream until the peer closes it, rather than
+    /// reading a single message. Each message is emitted as an
+    /// [`Event::ReceivedMessage`](crate::Event::ReceivedMessage) and the end of the stream as
+    /// [`Event::InboundStreamClosed`](crate::Event::InboundStreamClosed). Streams of a request
+    /// protocol are never reused. The stream is still subject to `send_recv_timeout` as a whole.
+    pub reuse_inbound_stream: bool,
+    /// The size of the buffer that an outbound message and its headers are written through, so
+    /// that small messages are written to the stream at once. Writes larger than the buffer go to
+    /// the stream directly, and 0 disables buffering.
+    pub write_buffer_size: usize,
+    /// The number of bytes read ahead of the current message on an inbound stream that carries
+    /// more than one message, so that the frames that follow it are buffered while it is
+    /// processed instead of being read a few bytes at a time. Only applies when
+    /// `reuse_inbound_stream` or `batch_window` is set, and 0 disables read-ahead.
+    pub inbound_read_ahead: usize,
+    /// If set, messages to a peer that are handed to a connection within this window of each
+    /// other are written to a single substream, up to
+    /// [`MAX_BATCH_SIZE`](crate::MAX_BATCH_SIZE) messages, instead of a substream each. Requests
+    /// and messages sent with a specific protocol are never batched. Each message is still
+    /// reported with its own event. The receiving peer must enable `batch_window` or
+    /// `reuse_inbound_stream` to read more than one message from a substream.
+    pub batch_window: Option<Duration>,
+    /// The number of recent dedup keys remembered for each peer, or 0 to disable deduplication.
+    /// Messages sent with [`Behaviour::send_message_with_dedup_key`](crate::Behaviour::send_message_with_dedup_key)
+    /// whose key is in the window are dropped. Must be enabled on both peers, since it changes the
+    /// wire format.
+    pub dedup_window: usize,
+    /// The maximum number of failed payloads kept for
+    /// [`Behaviour::take_failed`](crate::Behaviour::take_failed), or 0 to keep none.
+    pub retain_failed: usize,
+    /// The number of peers whose last dialed address is remembered and used to dial them again
+    /// once disconnected, or 0 to remember none. The least recently connected peers are
+    /// forgotten first.
+    pub remembered_addresses: usize,
+    /// If set, messages queued for a peer that is dialed to send them fail with
+    /// [`Error::DialTimeout`](crate::error::Error::DialTimeout) if the peer is not connected within
+    /// this duration, even if the dial itself neither succeeds nor fails. An additional connection
+    /// dialed for [`SaturationPolicy::OpenNewConnection`] is given up on after this duration
+    /// instead, and its messages keep waiting for the existing connections.
+    pub dial_timeout: Option<Duration>,
+    /// Protocols in order of preference when negotiating substreams. Supported protocols not
+    /// listed here are least preferred and keep the order they were given to the behaviour in.
+    pub protocol_preference: Vec<StreamProtocol>,
+    /// Orders supported protocols that are not listed in `protocol_preference` by the version in
+    /// their last path segment, highest first, for example `/app/2.0.0` before `/app/1.5.0`.
+    /// Protocols without a numeric version come last.
+    pub prefer_highest_version: bool,
+    /// Protocols used for requests sent with [`Behaviour::request`](crate::Behaviour::request).
+    /// Inbound substreams negotiated with one of these protocols are emitted as
+    /// [`Event::InboundRequest`](crate::Event::InboundRequest) and kept open for the response.
+    pub request_protocols: Vec<StreamProtocol>,
+    /// What to do with a message when every connection to the peer has
+    /// `max_concurrent_streams` messages in flight.
+    pub saturation_policy: SaturationPolicy,
+    /// What to do with a new inbound stream when a connection has `max_concurrent_streams` streams
+    /// in progress.
+    pub inbound_capacity_policy: CapacityPolicy,
+    /// The backoff between attempts to open an outbound substream that failed with an IO error.
+    pub io_retry_backoff: BackoffPolicy,
+    /// The number of events buffered for the stream returned by
+    /// [`Behaviour::event_stream`](crate::Behaviour::event_stream).
+    pub event_stream_capacity: usize,
+    /// Metrics that are updated as messages are sent and received.
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Metrics>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             max_concurrent_streams: 3,
+            max_total_concurrent_streams: None,
+            max_inbound_streams_per_peer: None,
             send_recv_timeout: Duration::from_secs(10),
+            substream_timeout: Duration::from_secs(10),
+            idle_connection_grace: Duration::from_secs(10),
+            max_pending_outbound_per_peer: 128,
+            max_message_size: None,
+            inbound_during_shutdown: InboundShutdownPolicy::default(),
+            id_scope: IdScope::default(),
+            initial_message_id: MessageId::default(),
+            sequence_gap_detection: false,
+            require_ack: false,
+            coalesce_bulk_failures: false,
+            requeue_undelivered: false,
+            decode_diagnostics: false,
+            reuse_inbound_stream: false,
+            write_buffer_size: 8 * 1024,
+            inbound_read_ahead: 0,
+            batch_window: None,
+            dedup_window: 0,
+            retain_failed: 0,
+            remembered_addresses: 0,
+            dial_timeout: None,
+            protocol_preference: Vec::new(),
+            prefer_highest_version: false,
+            request_protocols: Vec::new(),
+            saturation_policy: SaturationPolicy::default(),
+            inbound_capacity_policy: CapacityPolicy::default(),
+            io_retry_backoff: BackoffPolicy::default(),
+            event_stream_capacity: 64,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Checks that the config values are usable.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_concurrent_streams == 0 {
+            return Err(ConfigError::ZeroMaxConcurrentStreams);
+        }
+        if self.max_total_concurrent_streams == Some(0) {
+            return Err(ConfigError::ZeroMaxTotalConcurrentStreams);
+        }
+        if self.max_inbound_streams_per_peer == Some(0) {
+            return Err(ConfigError::ZeroMaxInboundStreamsPerPeer);
+        }
+        Ok(())
+    }
+
+    /// Returns the name of the first field that differs from `other` and can't be changed on a
+    /// running behaviour.
+    pub(crate) fn fixed_field_changed(&self, other: &Config) -> Option<&'static str> {
+        if self.max_total_concurrent_streams != other.max_total_concurrent_streams {
+            return Some("max_total_concurrent_streams");
+        }
+        if self.max_inbound_streams_per_peer != other.max_inbound_streams_per_peer {
+            return Some("max_inbound_streams_per_peer");
+        }
+        if self.id_scope != other.id_scope {
+            return Some("id_scope");
+        }
+        if self.initial_message_id != other.initial_message_id {
+            return Some("initial_message_id");
+        }
+        if self.sequence_gap_detection != other.sequence_gap_detection {
+            return Some("sequence_gap_detection");
+        }
+        if self.require_ack != other.require_ack {
+            return Some("require_ack");
+        }
+        if self.dedup_window != other.dedup_window {
+            return Some("dedup_window");
+        }
+        if self.protocol_preference != other.protocol_preference {
+            return Some("protocol_preference");
+        }
+        if self.prefer_highest_version != other.prefer_highest_version {
+            return Some("prefer_highest_version");
+        }
+        if self.request_protocols != other.request_protocols {
+            return Some("request_protocols");
+        }
+        None
+    }
+}
+
+/// Builds a [`Config`], starting from the default values.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn max_concurrent_streams(mut self, max_concurrent_streams: usize) -> Self {
+        self.config.max_concurrent_streams = max_concurrent_streams;
+        self
+    }
+
+    pub fn max_total_concurrent_streams(mut self, max_total_concurrent_streams: usize) -> Self {
+        self.config.max_total_concurrent_streams = Some(max_total_concurrent_streams);
+        self
+    }
+
+    pub fn max_inbound_streams_per_peer(mut self, max_inbound_streams_per_peer: usize) -> Self {
+        self.config.max_inbound_streams_per_peer = Some(max_inbound_streams_per_peer);
+        self
+    }
+
+    pub fn send_recv_timeout(mut self, send_recv_timeout: Duration) -> Self {
+        self.config.send_recv_timeout = send_recv_timeout;
+        self
+    }
+
+    pub fn substream_timeout(mut self, substream_timeout: Duration) -> Self {
+        self.config.substream_timeout = substream_timeout;
+        self
+    }
+
+    pub fn idle_connection_grace(mut self, idle_connection_grace: Duration) -> Self {
+        self.config.idle_connection_grace = idle_connection_grace;
+        self
+    }
+
+    pub fn max_pending_outbound_per_peer(mut self, max_pending_outbound_per_peer: usize) -> Self {
+        self.config.max_pending_outbound_per_peer = max_pending_outbound_per_peer;
+        self
+    }
+
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.config.max_message_size = Some(max_message_size);
+        self
+    }
+
+    pub fn inbound_during_shutdown(mut self, policy: InboundShutdownPolicy) -> Self {
+        self.config.inbound_during_shutdown = policy;
+        self
+    }
+
+    pub fn id_scope(mut self, id_scope: IdScope) -> Self {
+        self.config.id_scope = id_scope;
+        self
+    }
+
+    pub fn initial_message_id(mut self, initial_message_id: MessageId) -> Self {
+        self.config.initial_message_id = initial_message_id;
+        self
+    }
+
+    pub fn sequence_gap_detection(mut self, enabled: bool) -> Self {
+        self.config.sequence_gap_detection = enabled;
+        self
+    }
+
+    pub fn require_ack(mut self, require_ack: bool) -> Self {
+        self.config.require_ack = require_ack;
+        self
+    }
+
+    pub fn coalesce_bulk_failures(mut self, coalesce_bulk_failures: bool) -> Self {
+        self.config.coalesce_bulk_failures = coalesce_bulk_failures;
+        self
+    }
+
+    pub fn requeue_undelivered(mut self, requeue_undelivered: bool) -> Self {
+        self.config.requeue_undelivered = requeue_undelivered;
+        self
+    }
+
+    pub fn decode_diagnostics(mut self, decode_diagnostics: bool) -> Self {
+        self.config.decode_diagnostics = decode_diagnostics;
+        self
+    }
+
+    pub fn reuse_inbound_stream(mut self, reuse_inbound_stream: bool) -> Self {
+        self.config.reuse_inbound_stream = reuse_inbound_stream;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.config.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    pub fn inbound_read_ahead(mut self, inbound_read_ahead: usize) -> Self {
+        self.config.inbound_read_ahead = inbound_read_ahead;
+        self
+    }
+
+    pub fn batch_window(mut self, batch_window: Option<Duration>) -> Self {
+        self.config.batch_window = batch_window;
+        self
+    }
+
+    pub fn dedup_window(mut self, dedup_window: usize) -> Self {
+        self.config.dedup_window = dedup_window;
+        self
+    }
+
+    pub fn retain_failed(mut self, retain_failed: usize) -> Self {
+        self.config.retain_failed = retain_failed;
+        self
+    }
+
+    pub fn remembered_addresses(mut self, remembered_addresses: usize) -> Self {
+        self.config.remembered_addresses = remembered_addresses;
+        self
+    }
+
+    pub fn dial_timeout(mut self, dial_timeout: Option<Duration>) -> Self {
+        self.config.dial_timeout = dial_timeout;
+        self
+    }
+
+    pub fn protocol_preference<I>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = StreamProtocol>,
+    {
+        self.config.protocol_preference = protocols.into_iter().collect();
+        self
+    }
+
+    pub fn prefer_highest_version(mut self, prefer_highest_version: bool) -> Self {
+        self.config.prefer_highest_version = prefer_highest_version;
+        self
+    }
+
+    pub fn request_protocols<I>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = StreamProtocol>,
+    {
+        self.config.request_protocols = protocols.into_iter().collect();
+        self
+    }
+
+    pub fn saturation_policy(mut self, policy: SaturationPolicy) -> Self {
+        self.config.saturation_policy = policy;
+        self
+    }
+
+    pub fn inbound_capacity_policy(mut self, policy: CapacityPolicy) -> Self {
+        self.config.inbound_capacity_policy = policy;
+        self
+    }
+
+    pub fn io_retry_backoff(mut self, backoff: BackoffPolicy) -> Self {
+        self.config.io_retry_backoff = backoff;
+        self
+    }
+
+    pub fn event_stream_capacity(mut self, capacity: usize) -> Self {
+        self.config.event_stream_capacity = capacity;
+        self
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.config.metrics = Some(metrics);
+        self
+    }
+
+    /// Validates and returns the config.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Determines what happens to inbound messages that arrive while the behaviour is shutting down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InboundShutdownPolicy {
+    /// Inbound messages continue to be received and emitted as events.
+    #[default]
+    Deliver,
+    /// Inbound messages are read from the stream and silently discarded.
+    Drop,
+    /// New inbound streams are closed without being read.
+    Reject,
+}
+
+/// The scope in which outbound message ids are unique.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdScope {
+    /// Message ids are allocated from a single sequence shared by all peers.
+    #[default]
+    Global,
+    /// Message ids are allocated from a separate sequence for each peer, starting at 0.
+    PerPeer,
+}
+
+/// Determines what happens to an outbound message when all connections to the peer are saturated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SaturationPolicy {
+    /// Queue the message until a connection has capacity.
+    #[default]
+    Queue,
+    /// Queue the message and dial an additional connection to the peer, as long as there are fewer
+    /// than `max_connections` connections.
+    OpenNewConnection { max_connections: usize },
+    /// Fail the message with [`Error::AllConnectionsSaturated`](crate::error::Error::AllConnectionsSaturated).
+    Fail,
+}
+
+/// Determines what happens to a new inbound stream when the connection is at capacity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CapacityPolicy {
+    /// Drop the new stream.
+    #[default]
+    DropNewest,
+    /// Cancel the oldest inbound stream still in progress, reporting it as an
+    /// [`Event::InboundFailure`] with [`Error::Evicted`], and read the new stream once its slot is
+    /// free. The new stream is dropped if there is no inbound stream to evict.
+    ///
+    /// [`Event::InboundFailure`]: crate::Event::InboundFailure
+    /// [`Error::Evicted`]: crate::error::Error::Evicted
+    EvictOldest,
+}
+
+/// How long connections to a peer are kept alive while idle, as a multiple of
+/// [`Config::idle_connection_grace`]. Set with
+/// [`Behaviour::set_peer_priority`](crate::Behaviour::set_peer_priority).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionPriority {
+    /// Idle connections are closed after the swarm's idle connection timeout, without a grace.
+    Low,
+    /// Idle connections are kept alive for the grace before the swarm's idle connection timeout
+    /// starts.
+    #[default]
+    Normal,
+    /// Idle connections are kept alive for four times the grace before the swarm's idle
+    /// connection timeout starts.
+    High,
+}
+
+impl ConnectionPriority {
+    /// Scales the idle connection grace to this priority.
+    pub(crate) fn idle_grace(self, grace: Duration) -> Duration {
+        match self {
+            ConnectionPriority::Low => Duration::ZERO,
+            ConnectionPriority::Normal => grace,
+            ConnectionPriority::High => grace.saturating_mul(4),
+        }
+    }
+}
+
+/// An exponential backoff between retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackoffPolicy {
+    /// The delay before the first retry.
+    pub initial_delay: Duration,
+    /// The maximum delay between retries.
+    pub max_delay: Duration,
+    /// The factor the delay is multiplied by after each retry.
+    pub multiplier: u32,
+}
+
+impl BackoffPolicy {
+    /// Returns the delay before the given retry attempt, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .checked_pow(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2,
         }
     }
 }
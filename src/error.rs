@@ -1,28 +1,216 @@
-use futures_bounded::Timeout;
+use crate::DecodeDiagnostics;
+use libp2p::PeerId;
 use std::fmt::{Debug, Display, Formatter};
 use std::io;
 
 #[derive(Debug)]
 pub enum Error {
     DecodeError(io::Error),
+    /// The codec failed to encode a message.
+    EncodeError(io::Error),
+    /// Writing to the stream failed outside of the codec, such as while writing the headers of a
+    /// message or flushing or closing the stream.
+    WriteError(io::Error),
+    /// A decode error with diagnostics, emitted instead of [`Error::DecodeError`] if
+    /// [`Config::decode_diagnostics`](crate::Config::decode_diagnostics) is enabled.
+    CodecError {
+        error: io::Error,
+        diagnostics: DecodeDiagnostics,
+    },
     ConnectionClosed,
-    Timeout(Timeout),
+    Timeout(TimeoutPhase),
     DialFailure,
+    /// The peer was not connected within [`Config::dial_timeout`](crate::Config::dial_timeout) of
+    /// being dialed to send the message.
+    DialTimeout,
+    /// The message was addressed to the local peer, set with
+    /// [`Behaviour::with_local_peer_id`](crate::Behaviour::with_local_peer_id).
+    SelfDial,
     DialUpgradeError,
     ProtocolNotSupported,
+    /// The peer dropped the [`ResponseChannel`](crate::ResponseChannel) for the request without
+    /// sending a response.
+    ResponseOmitted,
+    /// The message was still queued when its time to live, given to
+    /// [`Behaviour::send_message_with_ttl`](crate::Behaviour::send_message_with_ttl), elapsed.
+    Expired,
+    QueueFull,
+    AllConnectionsSaturated,
+    /// The message is larger than [`Config::max_message_size`](crate::Config::max_message_size),
+    /// or than the codec's own limit as reported with
+    /// [`MessageTooLargeError`](crate::MessageTooLargeError).
+    MessageTooLarge {
+        len: usize,
+        limit: usize,
+    },
+    /// The limit on concurrent streams across all connections was reached, or the connection's
+    /// `max_concurrent_streams` were all taken by inbound streams when the outbound stream opened.
+    AtCapacity,
+    /// The peer has reached [`Config::max_inbound_streams_per_peer`](crate::Config::max_inbound_streams_per_peer),
+    /// so its inbound stream was dropped.
+    RateLimited {
+        peer_id: PeerId,
+    },
+    /// An inbound stream from the peer was cancelled to make room for a newer stream, as
+    /// configured by [`CapacityPolicy::EvictOldest`](crate::CapacityPolicy::EvictOldest).
+    Evicted {
+        peer_id: PeerId,
+    },
+    /// The codec panicked while encoding or decoding a message for the peer.
+    CodecPanic {
+        peer_id: PeerId,
+    },
+    #[cfg(feature = "message-store")]
+    MessageStore(io::Error),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::DecodeError(err) => write!(f, "Decode error: {}", err),
+            Self::EncodeError(err) => write!(f, "Encode error: {}", err),
+            Self::WriteError(err) => write!(f, "Write error: {}", err),
+            Self::CodecError { error, diagnostics } => {
+                write!(f, "Decode error: {} ({})", error, diagnostics)
+            }
             Self::ConnectionClosed => write!(f, "Connection closed"),
-            Self::Timeout(err) => write!(f, "Timeout: {}", err),
+            Self::Timeout(phase) => write!(f, "Timeout during {}", phase),
             Self::DialFailure => write!(f, "Dial failure"),
+            Self::DialTimeout => write!(f, "Dial timed out"),
+            Self::SelfDial => write!(f, "Attempted to send a message to the local peer"),
             Self::DialUpgradeError => write!(f, "Dial upgrade error"),
             Self::ProtocolNotSupported => write!(f, "Protocol not supported"),
+            Self::ResponseOmitted => write!(f, "Response omitted"),
+            Self::Expired => write!(f, "Message expired"),
+            Self::QueueFull => write!(f, "Outbound queue full"),
+            Self::AllConnectionsSaturated => write!(f, "All connections saturated"),
+            Self::MessageTooLarge { len, limit } => {
+                write!(
+                    f,
+                    "Message of {} bytes exceeds the limit of {} bytes",
+                    len, limit
+                )
+            }
+            Self::AtCapacity => write!(f, "At stream capacity"),
+            Self::RateLimited { peer_id } => write!(f, "Rate limited peer {}", peer_id),
+            Self::Evicted { peer_id } => write!(f, "Inbound stream from peer {} evicted", peer_id),
+            Self::CodecPanic { peer_id } => write!(f, "Codec panicked for peer {}", peer_id),
+            #[cfg(feature = "message-store")]
+            Self::MessageStore(err) => write!(f, "Message store error: {}", err),
         }
     }
 }
 
-impl std::error::Error for Error {}
+/// Converts an IO error, typically returned by a [`Codec`](crate::Codec), into
+/// [`Error::DecodeError`].
+///
+/// ```
+/// use libp2p_messaging::error::Error;
+///
+/// let io_err = std::io::Error::new(std::io::ErrorKind::InvalidData, "bad frame");
+/// let e: Error = io_err.into();
+/// assert!(matches!(e, Error::DecodeError(_)));
+/// ```
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::DecodeError(err)
+    }
+}
+
+impl Error {
+    /// Returns true if the failure is transient and sending the message again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Timeout(_)
+            | Self::DialFailure
+            | Self::DialTimeout
+            | Self::DialUpgradeError
+            | Self::ConnectionClosed
+            | Self::WriteError(_)
+            | Self::QueueFull
+            | Self::AllConnectionsSaturated
+            | Self::AtCapacity
+            | Self::RateLimited { .. }
+            | Self::Evicted { .. } => true,
+            Self::DecodeError(_)
+            | Self::EncodeError(_)
+            | Self::CodecError { .. }
+            | Self::MessageTooLarge { .. }
+            | Self::SelfDial
+            | Self::ProtocolNotSupported
+            | Self::ResponseOmitted
+            | Self::Expired
+            | Self::CodecPanic { .. } => false,
+            #[cfg(feature = "message-store")]
+            Self::MessageStore(_) => false,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::DecodeError(err)
+            | Self::EncodeError(err)
+            | Self::WriteError(err)
+            | Self::CodecError { error: err, .. } => Some(err),
+            #[cfg(feature = "message-store")]
+            Self::MessageStore(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The phase of a substream in which a timeout occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeoutPhase {
+    /// Negotiating the protocol for an outbound substream.
+    Negotiation,
+    /// Reading an inbound message.
+    Read,
+    /// Writing an outbound message.
+    Write,
+}
+
+impl Display for TimeoutPhase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Negotiation => write!(f, "negotiation"),
+            Self::Read => write!(f, "read"),
+            Self::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    NoProtocols,
+    ZeroMaxConcurrentStreams,
+    ZeroMaxTotalConcurrentStreams,
+    ZeroMaxInboundStreamsPerPeer,
+    /// The protocol name is not of the form `/{name}/{version}`.
+    InvalidProtocol(String),
+    /// The field can't be changed with [`Behaviour::set_config`](crate::Behaviour::set_config).
+    FixedAtRuntime(&'static str),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoProtocols => write!(f, "at least one protocol is required"),
+            Self::ZeroMaxConcurrentStreams => {
+                write!(f, "max_concurrent_streams must be greater than zero")
+            }
+            Self::ZeroMaxTotalConcurrentStreams => {
+                write!(f, "max_total_concurrent_streams must be greater than zero")
+            }
+            Self::ZeroMaxInboundStreamsPerPeer => {
+                write!(f, "max_inbound_streams_per_peer must be greater than zero")
+            }
+            Self::InvalidProtocol(protocol) => write!(f, "invalid protocol name: {}", protocol),
+            Self::FixedAtRuntime(field) => write!(f, "{} can't be changed at runtime", field),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -1,25 +1,200 @@
 use crate::error::Error;
-use crate::MessageId;
-use libp2p::PeerId;
+use crate::{MessageId, RequestId, ResponseChannel};
+use libp2p::swarm::ConnectionId;
+use libp2p::{Multiaddr, PeerId, StreamProtocol};
 
+/// Events emitted by the [`Behaviour`](crate::Behaviour). Connection handlers emit the same type,
+/// so every variant carries the peer and, where one exists, the id of the message it relates to.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Event<TMsg> {
+    /// A message was received from a peer.
     ReceivedMessage {
         peer_id: PeerId,
+        /// The protocol negotiated for the inbound substream.
+        protocol: StreamProtocol,
+        /// The current remote address of the connection the message was received on. This may be
+        /// `None`, for example for relayed connections.
+        remote_address: Option<Multiaddr>,
         message: TMsg,
+        /// The number of bytes the codec read for the message, including any length prefix.
+        size: usize,
     },
+    /// A request was received from a peer. The response is sent using the channel.
+    InboundRequest {
+        peer_id: PeerId,
+        /// The protocol negotiated for the inbound substream.
+        protocol: StreamProtocol,
+        request_id: RequestId,
+        message: TMsg,
+        channel: ResponseChannel<TMsg>,
+    },
+    /// A response was received for a request sent with
+    /// [`Behaviour::request`](crate::Behaviour::request).
+    Response {
+        peer_id: PeerId,
+        /// The protocol negotiated for the outbound substream.
+        protocol: StreamProtocol,
+        request_id: RequestId,
+        message: TMsg,
+    },
+    /// An outbound message was fully written to a substream.
     MessageSent {
+        peer_id: PeerId,
+        message_id: MessageId,
+        /// The number of bytes the codec wrote for the message, including any length prefix.
+        size: usize,
+    },
+    /// The remote peer acknowledged that it decoded an outbound message. Emitted instead of
+    /// [`Event::MessageSent`] if [`Config::require_ack`](crate::Config::require_ack) is enabled.
+    MessageAcked {
+        peer_id: PeerId,
+        message_id: MessageId,
+        /// The number of bytes the codec wrote for the message, including any length prefix.
+        size: usize,
+    },
+    /// The peer is not connected, so the message was queued while it is dialed. The message is
+    /// sent once a connection is established, or fails with [`Error::DialFailure`].
+    OutboundQueued {
+        peer_id: PeerId,
+        message_id: MessageId,
+    },
+    /// A queued message was cancelled with
+    /// [`Behaviour::cancel_message`](crate::Behaviour::cancel_message) before it was sent.
+    MessageCancelled {
+        peer_id: PeerId,
         message_id: MessageId,
     },
+    /// An inbound stream failed before its message was received, or before the response to its
+    /// request was sent.
     InboundFailure {
         peer_id: PeerId,
+        /// The id of the inbound stream, which is the request id of an
+        /// [`Event::InboundRequest`] received on it. This is unrelated to the ids of outbound
+        /// messages.
         message_id: MessageId,
+        /// The current remote address of the connection the stream was opened on. This may be
+        /// `None`, for example for relayed connections.
+        remote_address: Option<Multiaddr>,
         error: Error,
     },
+    /// The messages pending on a closed connection could not be sent. Emitted instead of an
+    /// [`Event::OutboundFailure`] per message if
+    /// [`Config::coalesce_bulk_failures`](crate::Config::coalesce_bulk_failures) is enabled.
+    BulkFailure {
+        peer_id: PeerId,
+        message_ids: Vec<MessageId>,
+        /// The remote address of the connection the failure occurred on, if known.
+        remote_address: Option<Multiaddr>,
+        error: Error,
+    },
+    /// An outbound message could not be sent.
     OutboundFailure {
         peer_id: PeerId,
         message_id: MessageId,
         error: Error,
     },
+    /// A connection to a peer was established and is ready for messaging.
+    PeerConnected {
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        remote_address: Option<Multiaddr>,
+    },
+    /// The first substream on a connection was negotiated with the given protocol. Useful when
+    /// several versions of a protocol are supported, to learn which version the peer speaks. The
+    /// protocol of each message is also reported with [`Event::InboundRequest`] and
+    /// [`Event::Response`].
+    ProtocolNegotiated {
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        protocol: StreamProtocol,
+    },
+    /// The peer closed an inbound stream that was kept open for multiple messages. Only emitted
+    /// if [`Config::reuse_inbound_stream`](crate::Config::reuse_inbound_stream) is enabled.
+    InboundStreamClosed { peer_id: PeerId },
+    /// The last connection to a peer was closed.
+    PeerDisconnected { peer_id: PeerId },
+    /// Messages written to the connection by the peer with sequence numbers from `expected` up to,
+    /// but not including, `got` were not received. Only emitted if
+    /// [`Config::sequence_gap_detection`] is enabled.
+    ///
+    /// [`Config::sequence_gap_detection`]: crate::Config::sequence_gap_detection
+    SequenceGap {
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        expected: u64,
+        got: u64,
+    },
+    /// An inbound message or request was dropped because the
+    /// [inbound filter](crate::Behaviour::with_inbound_filter) rejected it.
+    InboundRejected { peer_id: PeerId },
+    /// A message was received with a dedup key that is still within the
+    /// [`Config::dedup_window`](crate::Config::dedup_window), so it was dropped rather than
+    /// delivered again.
+    DuplicateDropped { peer_id: PeerId, dedup_key: u64 },
+    /// Emitted once a peer passed to [`Behaviour::flush_peer`](crate::Behaviour::flush_peer) has
+    /// no more pending outbound messages.
+    PeerFlushed { peer_id: PeerId },
+    /// An error that could not be attributed to a specific message.
     Error(Error),
 }
+
+impl<TMsg> Event<TMsg> {
+    /// Returns the peer the event relates to, if any. For [`Event::Error`], this is the peer
+    /// carried by the error, if any.
+    pub fn peer_id(&self) -> Option<PeerId> {
+        match self {
+            Self::ReceivedMessage { peer_id, .. }
+            | Self::InboundRequest { peer_id, .. }
+            | Self::Response { peer_id, .. }
+            | Self::MessageSent { peer_id, .. }
+            | Self::MessageAcked { peer_id, .. }
+            | Self::OutboundQueued { peer_id, .. }
+            | Self::MessageCancelled { peer_id, .. }
+            | Self::InboundFailure { peer_id, .. }
+            | Self::BulkFailure { peer_id, .. }
+            | Self::OutboundFailure { peer_id, .. }
+            | Self::PeerConnected { peer_id, .. }
+            | Self::ProtocolNegotiated { peer_id, .. }
+            | Self::InboundStreamClosed { peer_id }
+            | Self::PeerDisconnected { peer_id }
+            | Self::SequenceGap { peer_id, .. }
+            | Self::InboundRejected { peer_id }
+            | Self::DuplicateDropped { peer_id, .. }
+            | Self::PeerFlushed { peer_id } => Some(*peer_id),
+            Self::Error(
+                Error::RateLimited { peer_id }
+                | Error::CodecPanic { peer_id }
+                | Error::Evicted { peer_id },
+            ) => Some(*peer_id),
+            Self::Error(_) => None,
+        }
+    }
+
+    /// Returns the id of the outbound message the event relates to, if any. This includes the
+    /// request id of an [`Event::Response`], which is the id of the outbound request, but not the
+    /// inbound ids of an [`Event::InboundRequest`] or [`Event::InboundFailure`].
+    pub fn message_id(&self) -> Option<MessageId> {
+        match self {
+            Self::Response { request_id, .. } => Some(*request_id),
+            Self::MessageSent { message_id, .. }
+            | Self::MessageAcked { message_id, .. }
+            | Self::OutboundQueued { message_id, .. }
+            | Self::MessageCancelled { message_id, .. }
+            | Self::OutboundFailure { message_id, .. } => Some(*message_id),
+            Self::ReceivedMessage { .. }
+            | Self::InboundRequest { .. }
+            | Self::InboundFailure { .. }
+            | Self::BulkFailure { .. }
+            | Self::PeerConnected { .. }
+            | Self::ProtocolNegotiated { .. }
+            | Self::InboundStreamClosed { .. }
+            | Self::PeerDisconnected { .. }
+            | Self::SequenceGap { .. }
+            | Self::InboundRejected { .. }
+            | Self::DuplicateDropped { .. }
+            | Self::PeerFlushed { .. }
+            | Self::Error(_) => None,
+        }
+    }
+}
@@ -1,118 +1,1193 @@
-use crate::codec::Codec;
-use crate::error::Error;
+use crate::codec::{Codec, CountingWriter, DiagnosticReader, MessageTooLargeError};
+use crate::error::{Error, TimeoutPhase};
 use crate::event::Event;
-use crate::{Config, OutboundMessage, EMPTY_QUEUE_SHRINK_THRESHOLD};
+use crate::{
+    priority_position, BackoffPolicy, CapacityPolicy, Config, ConnectionPriority, InboundFilter,
+    InboundShutdownPolicy, MessageId, OutboundMessage, RequestId, ResponseChannel,
+    EMPTY_QUEUE_SHRINK_THRESHOLD, MAX_BATCH_SIZE,
+};
+use futures_timer::Delay;
 use libp2p::core::UpgradeInfo;
-use libp2p::futures::FutureExt;
+use libp2p::futures::channel::{mpsc, oneshot};
+use libp2p::futures::future::{self, BoxFuture, Either};
+use libp2p::futures::io::{BufReader, BufWriter};
+use libp2p::futures::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt, SinkExt, StreamExt,
+};
 use libp2p::swarm::handler::{
     ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
-    ListenUpgradeError,
+    ListenUpgradeError, ProtocolsChange,
 };
 use libp2p::swarm::{
-    ConnectionHandler, ConnectionHandlerEvent, StreamUpgradeError, SubstreamProtocol,
+    ConnectionHandler, ConnectionHandlerEvent, ConnectionId, StreamUpgradeError, SubstreamProtocol,
 };
 use libp2p::{InboundUpgrade, OutboundUpgrade, PeerId, Stream, StreamProtocol};
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::convert::Infallible;
 use std::future::{ready, Ready};
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+/// Written back by the receiver once a message is decoded, if acknowledgements are required.
+const ACK: u8 = 1;
+/// A decoded length prefix at least this many times the codec's limit is more likely to be the
+/// start of a message framed differently than an oversized message.
+const FRAMING_MISMATCH_FACTOR: usize = 16;
+
+/// An inbound stream task, which reports at most one event once the stream is done.
+type InboundTask<TMsg> = BoxFuture<'static, Option<Event<TMsg>>>;
 
 pub struct Handler<TCodec: Codec> {
     peer_id: PeerId,
-    protocol: StreamProtocol,
+    connection_id: ConnectionId,
+    /// Whether a substream has been negotiated on the connection, which is reported once.
+    is_protocol_negotiated: bool,
+    protocols: Vec<StreamProtocol>,
+    request_protocols: Vec<StreamProtocol>,
     requested_outbound: VecDeque<OutboundMessage<TCodec::Message>>,
+    /// Messages in `requested_outbound` that have already been failed because the remote stopped
+    /// supporting their protocols. Their substreams are discarded once negotiated.
+    abandoned_outbound: HashSet<MessageId>,
+    /// Protocols the remote has reported that it no longer supports.
+    remote_removed_protocols: HashSet<StreamProtocol>,
     pending_outbound: VecDeque<OutboundMessage<TCodec::Message>>,
+    /// Messages waiting to retry opening an outbound substream, and when to retry.
+    delayed_outbound: Vec<(Instant, OutboundMessage<TCodec::Message>)>,
+    /// The number of failed attempts to open an outbound substream for each message.
+    retry_attempts: HashMap<MessageId, u32>,
+    retry_timer: Option<(Instant, Delay)>,
+    io_retry_backoff: BackoffPolicy,
+    substream_timeout: Duration,
+    /// The idle grace of the connection before it is scaled by `priority`.
+    idle_connection_grace: Duration,
+    /// When the connection became idle, and the timer that ends its idle grace.
+    idle_timer: Option<(Instant, Delay)>,
     pending_events: VecDeque<Event<TCodec::Message>>,
     codec: TCodec,
-    tasks: futures_bounded::FuturesSet<Event<TCodec::Message>>,
+    tasks: futures_bounded::FuturesMap<TaskId, Option<Event<TCodec::Message>>>,
+    /// The ids of the outbound messages in `tasks`, along with the ids of the messages batched on
+    /// the same substream.
+    outbound_in_progress: HashMap<MessageId, Vec<MessageId>>,
+    /// The ids of the inbound streams in `tasks`.
+    inbound_in_progress: HashSet<MessageId>,
+    batch_window: Option<Duration>,
+    /// Started once a batchable message is at the front of `pending_outbound`.
+    batch_timer: Option<Delay>,
+    /// Messages to be written to the substream requested for another message, by the id of that
+    /// message.
+    batched_outbound: HashMap<MessageId, Vec<OutboundMessage<TCodec::Message>>>,
+    /// Set once the connection is closing and the unfinished messages have been failed.
+    is_closing: bool,
+    /// The timeout and capacity for `tasks` from an updated config, applied once no tasks are in
+    /// progress.
+    pending_task_limits: Option<(Duration, usize)>,
+    /// The capacity of `tasks`, checked before pushing a task that must not be lost if the map is
+    /// full, since a rejected future is only returned borrowed from the map.
+    task_capacity: usize,
+    inbound_capacity_policy: CapacityPolicy,
+    /// Cancels the inbound tasks in progress, oldest first. Only used with
+    /// [`CapacityPolicy::EvictOldest`].
+    inbound_cancellations: VecDeque<oneshot::Sender<()>>,
+    /// An inbound task waiting for the slot of an evicted task.
+    deferred_inbound: Option<(TaskId, InboundTask<TCodec::Message>)>,
+    shared_state: Arc<SharedState>,
+    /// Stream tasks send events that occur before the task completes through this channel, such as
+    /// a request that is waiting for a response, along with the outcomes of batched messages.
+    inbound_event_sender: mpsc::Sender<Event<TCodec::Message>>,
+    inbound_event_receiver: mpsc::Receiver<Event<TCodec::Message>>,
+    sequence_gap_detection: bool,
+    /// The sequence number of the next message written to this connection.
+    outbound_sequence: Arc<AtomicU64>,
+    inbound_sequence: Arc<Mutex<InboundSequence>>,
+    /// How many later sequence numbers are received before a missing one is reported.
+    sequence_window: usize,
+    require_ack: bool,
+    decode_diagnostics: bool,
+    reuse_inbound_stream: bool,
+    write_buffer_size: usize,
+    inbound_read_ahead: usize,
+    inbound_during_shutdown: InboundShutdownPolicy,
+    is_shutting_down: bool,
+    priority: ConnectionPriority,
+    inbound_filter: Option<InboundFilter<TCodec::Message>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<crate::Metrics>,
+    #[cfg(feature = "testing")]
+    debug_state: Option<std::sync::Arc<std::sync::Mutex<HandlerDebugState>>>,
 }
 
 impl<TCodec: Codec> Handler<TCodec> {
-    pub fn new(peer_id: PeerId, protocol: StreamProtocol, config: &Config) -> Self {
+    pub(crate) fn new(
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        protocols: Vec<StreamProtocol>,
+        codec: TCodec,
+        shared_state: Arc<SharedState>,
+        config: &Config,
+    ) -> Self {
+        let (inbound_event_sender, inbound_event_receiver) =
+            mpsc::channel(config.max_concurrent_streams);
         Self {
             peer_id,
-            protocol,
+            connection_id,
+            is_protocol_negotiated: false,
+            protocols,
+            request_protocols: config.request_protocols.clone(),
             requested_outbound: VecDeque::new(),
+            abandoned_outbound: HashSet::new(),
+            remote_removed_protocols: HashSet::new(),
             pending_outbound: VecDeque::new(),
+            delayed_outbound: Vec::new(),
+            retry_attempts: HashMap::new(),
+            retry_timer: None,
+            io_retry_backoff: config.io_retry_backoff,
+            substream_timeout: config.substream_timeout,
+            idle_connection_grace: config.idle_connection_grace,
+            idle_timer: None,
             pending_events: VecDeque::new(),
-            codec: TCodec::default(),
-            tasks: futures_bounded::FuturesSet::new(
+            codec,
+            tasks: futures_bounded::FuturesMap::new(
                 config.send_recv_timeout,
                 config.max_concurrent_streams,
             ),
+            outbound_in_progress: HashMap::new(),
+            inbound_in_progress: HashSet::new(),
+            batch_window: config.batch_window,
+            batch_timer: None,
+            batched_outbound: HashMap::new(),
+            is_closing: false,
+            pending_task_limits: None,
+            task_capacity: config.max_concurrent_streams,
+            inbound_capacity_policy: config.inbound_capacity_policy,
+            inbound_cancellations: VecDeque::new(),
+            deferred_inbound: None,
+            shared_state,
+            inbound_event_sender,
+            inbound_event_receiver,
+            sequence_gap_detection: config.sequence_gap_detection,
+            outbound_sequence: Arc::new(AtomicU64::new(0)),
+            inbound_sequence: Arc::new(Mutex::new(InboundSequence::default())),
+            sequence_window: config.max_concurrent_streams,
+            require_ack: config.require_ack,
+            decode_diagnostics: config.decode_diagnostics,
+            reuse_inbound_stream: config.reuse_inbound_stream,
+            write_buffer_size: config.write_buffer_size,
+            inbound_read_ahead: config.inbound_read_ahead,
+            inbound_during_shutdown: config.inbound_during_shutdown,
+            is_shutting_down: false,
+            priority: ConnectionPriority::default(),
+            inbound_filter: None,
+            #[cfg(feature = "metrics")]
+            metrics: config.metrics.clone(),
+            #[cfg(feature = "testing")]
+            debug_state: None,
+        }
+    }
+
+    pub(crate) fn shutdown(&mut self) {
+        self.is_shutting_down = true;
+    }
+
+    pub(crate) fn set_priority(&mut self, priority: ConnectionPriority) {
+        self.priority = priority;
+        self.reset_idle_timer();
+    }
+
+    /// Restarts the idle grace timer with the current grace, keeping when the connection became
+    /// idle.
+    fn reset_idle_timer(&mut self) {
+        let grace = self.priority.idle_grace(self.idle_connection_grace);
+        if let Some((idle_since, timer)) = self.idle_timer.as_mut() {
+            *timer = Delay::new(grace.saturating_sub(idle_since.elapsed()));
+        }
+    }
+
+    /// Queues a message for an outbound substream, ahead of queued messages with a lower priority.
+    fn enqueue_outbound(&mut self, message: OutboundMessage<TCodec::Message>) {
+        match priority_position(&self.pending_outbound, message.priority) {
+            Some(ix) => self.pending_outbound.insert(ix, message),
+            None => self.pending_outbound.push_back(message),
+        }
+    }
+
+    pub(crate) fn set_inbound_filter(&mut self, filter: InboundFilter<TCodec::Message>) {
+        self.inbound_filter = Some(filter);
+    }
+}
+
+/// A snapshot of a connection handler's internal queues, for diagnosing stuck or dropped messages.
+#[cfg(feature = "testing")]
+#[derive(Debug, Clone)]
+pub struct HandlerDebugState {
+    pub peer_id: PeerId,
+    pub protocols: Vec<StreamProtocol>,
+    /// Messages for which an outbound substream has been requested but not yet negotiated.
+    pub requested_outbound: usize,
+    /// Messages waiting for an outbound substream to be requested.
+    pub pending_outbound: usize,
+    /// Events waiting to be delivered to the behaviour.
+    pub pending_events: usize,
+    /// Inbound and outbound stream tasks in progress.
+    pub tasks: usize,
+}
+
+/// State shared by the handlers of all connections.
+#[derive(Debug)]
+pub(crate) struct SharedState {
+    next_inbound_request_id: AtomicU64,
+    /// The number of stream tasks in progress across all handlers.
+    num_streams: AtomicUsize,
+    max_streams: Option<usize>,
+    /// The number of inbound stream tasks in progress for each peer, if limited.
+    inbound_streams: Mutex<HashMap<PeerId, usize>>,
+    max_inbound_streams_per_peer: Option<usize>,
+    /// The most recent dedup keys received from each peer.
+    dedup_keys: Mutex<HashMap<PeerId, DedupWindow>>,
+    dedup_window: usize,
+    /// The number of outbound substreams opened on each connection.
+    substreams_opened: Mutex<HashMap<ConnectionId, u64>>,
+}
+
+impl SharedState {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            next_inbound_request_id: AtomicU64::new(0),
+            num_streams: AtomicUsize::new(0),
+            max_streams: config.max_total_concurrent_streams,
+            inbound_streams: Mutex::new(HashMap::new()),
+            max_inbound_streams_per_peer: config.max_inbound_streams_per_peer,
+            dedup_keys: Mutex::new(HashMap::new()),
+            dedup_window: config.dedup_window,
+            substreams_opened: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record_substream_opened(&self, connection_id: ConnectionId) {
+        *self
+            .substreams_opened
+            .lock()
+            .expect("substream count lock poisoned")
+            .entry(connection_id)
+            .or_default() += 1;
+    }
+
+    /// The number of outbound substreams the connection's handler has opened.
+    pub(crate) fn substreams_opened(&self, connection_id: ConnectionId) -> u64 {
+        self.substreams_opened
+            .lock()
+            .expect("substream count lock poisoned")
+            .get(&connection_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn remove_connection(&self, connection_id: ConnectionId) {
+        self.substreams_opened
+            .lock()
+            .expect("substream count lock poisoned")
+            .remove(&connection_id);
+    }
+
+    /// Records a dedup key received from the peer, returning false if the key is already in the
+    /// peer's window.
+    fn record_dedup_key(&self, peer_id: PeerId, dedup_key: u64) -> bool {
+        let mut dedup_keys = self.dedup_keys.lock().expect("dedup lock poisoned");
+        let window = dedup_keys.entry(peer_id).or_default();
+        if !window.keys.insert(dedup_key) {
+            return false;
+        }
+        window.order.push_back(dedup_key);
+        if window.order.len() > self.dedup_window {
+            if let Some(oldest) = window.order.pop_front() {
+                window.keys.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Reserves a stream slot, returning `None` if the limit across all handlers has been reached.
+    fn try_acquire_stream(self: &Arc<Self>) -> Option<StreamPermit> {
+        let acquired = self
+            .num_streams
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                let at_capacity = matches!(self.max_streams, Some(max) if n >= max);
+                (!at_capacity).then_some(n + 1)
+            })
+            .is_ok();
+        acquired.then(|| StreamPermit {
+            shared_state: self.clone(),
+            inbound_peer: None,
+        })
+    }
+
+    /// Reserves a stream slot for an inbound stream from the peer, failing with
+    /// [`Error::RateLimited`] if the peer has reached its inbound stream limit or
+    /// [`Error::AtCapacity`] if the limit across all handlers has been reached.
+    fn try_acquire_inbound_stream(
+        self: &Arc<Self>,
+        peer_id: PeerId,
+    ) -> Result<StreamPermit, Error> {
+        let Some(max) = self.max_inbound_streams_per_peer else {
+            return self.try_acquire_stream().ok_or(Error::AtCapacity);
+        };
+        {
+            let mut inbound_streams = self
+                .inbound_streams
+                .lock()
+                .expect("inbound stream lock poisoned");
+            let count = inbound_streams.entry(peer_id).or_default();
+            if *count >= max {
+                return Err(Error::RateLimited { peer_id });
+            }
+            *count += 1;
+        }
+        let Some(mut permit) = self.try_acquire_stream() else {
+            self.release_inbound_stream(&peer_id);
+            return Err(Error::AtCapacity);
+        };
+        permit.inbound_peer = Some(peer_id);
+        Ok(permit)
+    }
+
+    fn release_inbound_stream(&self, peer_id: &PeerId) {
+        let mut inbound_streams = self
+            .inbound_streams
+            .lock()
+            .expect("inbound stream lock poisoned");
+        if let Some(count) = inbound_streams.get_mut(peer_id) {
+            *count -= 1;
+            if *count == 0 {
+                inbound_streams.remove(peer_id);
+            }
         }
     }
+
+    fn next_inbound_request_id(&self) -> RequestId {
+        MessageId::new(self.next_inbound_request_id.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The dedup keys received from a peer, bounded by [`Config::dedup_window`].
+#[derive(Debug, Default)]
+struct DedupWindow {
+    keys: HashSet<u64>,
+    /// The keys in `keys`, oldest first.
+    order: VecDeque<u64>,
+}
+
+/// The sequence numbers received on a connection, used to detect skipped messages.
+#[derive(Debug, Default)]
+struct InboundSequence {
+    /// The lowest sequence number not yet received.
+    next: u64,
+    /// Sequence numbers received ahead of `next`.
+    ahead: BTreeSet<u64>,
+}
+
+impl InboundSequence {
+    /// Records a received sequence number. Returns the range of sequence numbers that were skipped
+    /// once `window` later sequence numbers have been received without them, since messages on
+    /// concurrent streams may arrive out of order.
+    fn record(&mut self, got: u64, window: usize) -> Option<(u64, u64)> {
+        if got < self.next || !self.ahead.insert(got) {
+            return None;
+        }
+        self.advance();
+        if self.ahead.len() < window.max(1) {
+            return None;
+        }
+        let expected = self.next;
+        self.next = *self.ahead.first().expect("checked not empty");
+        let got = self.next;
+        self.advance();
+        Some((expected, got))
+    }
+
+    fn advance(&mut self) {
+        while self.ahead.remove(&self.next) {
+            self.next += 1;
+        }
+    }
+}
+
+/// Reads the dedup header written by the sender: a flag byte, followed by the key if the flag is set.
+async fn read_dedup_key<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<u64>> {
+    let mut flag = [0u8; 1];
+    reader.read_exact(&mut flag).await?;
+    if flag[0] == 0 {
+        return Ok(None);
+    }
+    let mut key = [0u8; 8];
+    reader.read_exact(&mut key).await?;
+    Ok(Some(u64::from_be_bytes(key)))
+}
+
+/// Acknowledges a decoded message, closing the stream unless it is reused for further messages.
+async fn acknowledge<S: AsyncWrite + Unpin>(stream: &mut S, close: bool) -> io::Result<()> {
+    stream.write_all(&[ACK]).await?;
+    if close {
+        stream.close().await
+    } else {
+        stream.flush().await
+    }
+}
+
+/// Decodes a message from the stream, returning it along with the number of bytes the codec read.
+/// If `diagnostics` is enabled, [`DecodeDiagnostics`](crate::DecodeDiagnostics) are attached to the
+/// error.
+async fn decode_message<TCodec: Codec, R: AsyncRead + Unpin + Send>(
+    codec: &mut TCodec,
+    reader: &mut R,
+    diagnostics: bool,
+) -> Result<(TCodec::Message, usize), Error> {
+    let mut reader = DiagnosticReader::new(reader, diagnostics);
+    let result = codec.decode_from(&mut reader).await;
+    if let Err(error) = &result {
+        if let Some(&MessageTooLargeError { len, limit }) =
+            error.get_ref().and_then(|e| e.downcast_ref())
+        {
+            if len / limit.max(1) >= FRAMING_MISMATCH_FACTOR {
+                tracing::warn!(
+                    "decoded a length prefix of {len} bytes, far beyond the limit of {limit} \
+                     bytes. The remote may be using a different framing than the local codec \
+                     ({})",
+                    codec.framing()
+                );
+            }
+            return Err(Error::MessageTooLarge { len, limit });
+        }
+    }
+    match result {
+        Ok(message) => Ok((message, reader.bytes_read())),
+        Err(error) if diagnostics => Err(Error::CodecError {
+            error,
+            diagnostics: reader.into_diagnostics(codec.framing().prefix),
+        }),
+        Err(error) => Err(Error::DecodeError(error)),
+    }
+}
+
+/// Writes the headers of an outbound message followed by the message, returning the number of
+/// bytes the codec wrote for the message.
+async fn write_message<TCodec: Codec + Send, W: AsyncWrite + Unpin + Send>(
+    codec: &mut TCodec,
+    writer: &mut W,
+    message: OutboundMessage<TCodec::Message>,
+    sequence: Option<&AtomicU64>,
+    dedup: bool,
+) -> Result<usize, Error> {
+    // Numbered as it is written, so messages that never reach a stream don't leave a gap
+    if let Some(sequence) = sequence {
+        let sequence = sequence.fetch_add(1, Ordering::Relaxed);
+        writer
+            .write_all(&sequence.to_be_bytes())
+            .await
+            .map_err(Error::WriteError)?;
+    }
+    if dedup {
+        let header = match message.dedup_key {
+            Some(key) => [&[1][..], &key.to_be_bytes()].concat(),
+            None => vec![0],
+        };
+        writer.write_all(&header).await.map_err(Error::WriteError)?;
+    }
+
+    // A panicking codec should only fail this stream rather than the whole connection
+    let peer_id = message.peer_id;
+    let mut counting_writer = CountingWriter::new(writer);
+    let result = AssertUnwindSafe(encode(codec, &mut counting_writer, message.message))
+        .catch_unwind()
+        .await;
+    match result {
+        Ok(Ok(())) => Ok(counting_writer.bytes_written()),
+        Ok(Err(e)) => Err(Error::EncodeError(e)),
+        Err(_) => {
+            tracing::error!("codec panicked while encoding message for {peer_id}");
+            Err(Error::CodecPanic { peer_id })
+        }
+    }
+}
+
+/// Encodes a message to the stream, writing the buffer from [`Codec::encode_to_bytes`] behind a
+/// u32 length prefix if the codec prefers it.
+async fn encode<TCodec: Codec + Send, W: AsyncWrite + Unpin + Send>(
+    codec: &mut TCodec,
+    writer: &mut W,
+    message: TCodec::Message,
+) -> io::Result<()> {
+    if !codec.prefers_bytes() {
+        return codec.encode_to(writer, message).await;
+    }
+    let body = codec.encode_to_bytes(message).await?;
+    let len = u32::try_from(body.len()).map_err(|_| MessageTooLargeError {
+        len: body.len(),
+        limit: u32::MAX as usize,
+    })?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(&body).await
+}
+
+/// Reports an error from the inbound stream with the given id as an [`Event::InboundFailure`].
+fn inbound_failure<TMsg>(
+    peer_id: PeerId,
+    message_id: MessageId,
+    event: Event<TMsg>,
+) -> Event<TMsg> {
+    match event {
+        Event::Error(error) => Event::InboundFailure {
+            peer_id,
+            message_id,
+            // Filled in by the behaviour, which tracks the connection's address
+            remote_address: None,
+            error,
+        },
+        event => event,
+    }
+}
+
+/// Fails every message written to a stream with the error the stream failed with.
+fn fail_stream<TMsg>(peer_id: PeerId, message_ids: &[MessageId], error: Error) -> Vec<Event<TMsg>> {
+    let Some((&last, rest)) = message_ids.split_last() else {
+        return Vec::new();
+    };
+    let mut events = rest
+        .iter()
+        .map(|&message_id| Event::OutboundFailure {
+            peer_id,
+            message_id,
+            error: copy_stream_error(&error),
+        })
+        .collect::<Vec<_>>();
+    events.push(Event::OutboundFailure {
+        peer_id,
+        message_id: last,
+        error,
+    });
+    events
+}
+
+/// Copies an error that failed a stream for each message batched on the stream. IO errors keep
+/// their kind and message.
+fn copy_stream_error(error: &Error) -> Error {
+    let copy_io = |e: &io::Error| io::Error::new(e.kind(), e.to_string());
+    match error {
+        Error::DecodeError(e) => Error::DecodeError(copy_io(e)),
+        Error::EncodeError(e) => Error::EncodeError(copy_io(e)),
+        Error::WriteError(e) => Error::WriteError(copy_io(e)),
+        Error::CodecError { error, diagnostics } => Error::CodecError {
+            error: copy_io(error),
+            diagnostics: diagnostics.clone(),
+        },
+        Error::ConnectionClosed => Error::ConnectionClosed,
+        Error::Timeout(phase) => Error::Timeout(*phase),
+        Error::DialFailure => Error::DialFailure,
+        Error::DialTimeout => Error::DialTimeout,
+        Error::SelfDial => Error::SelfDial,
+        Error::DialUpgradeError => Error::DialUpgradeError,
+        Error::ProtocolNotSupported => Error::ProtocolNotSupported,
+        Error::ResponseOmitted => Error::ResponseOmitted,
+        Error::Expired => Error::Expired,
+        Error::QueueFull => Error::QueueFull,
+        Error::AllConnectionsSaturated => Error::AllConnectionsSaturated,
+        Error::MessageTooLarge { len, limit } => Error::MessageTooLarge {
+            len: *len,
+            limit: *limit,
+        },
+        Error::AtCapacity => Error::AtCapacity,
+        Error::RateLimited { peer_id } => Error::RateLimited { peer_id: *peer_id },
+        Error::Evicted { peer_id } => Error::Evicted { peer_id: *peer_id },
+        Error::CodecPanic { peer_id } => Error::CodecPanic { peer_id: *peer_id },
+        #[cfg(feature = "message-store")]
+        Error::MessageStore(e) => Error::MessageStore(copy_io(e)),
+    }
+}
+
+/// A stream slot that is released when the stream task completes or is dropped.
+struct StreamPermit {
+    shared_state: Arc<SharedState>,
+    /// The peer whose inbound stream count this permit holds, if limited.
+    inbound_peer: Option<PeerId>,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.shared_state.num_streams.fetch_sub(1, Ordering::AcqRel);
+        if let Some(peer_id) = self.inbound_peer {
+            self.shared_state.release_inbound_stream(&peer_id);
+        }
+    }
+}
+
+/// Identifies a stream task so that a timeout can be attributed to the phase it occurred in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum TaskId {
+    /// Writing the outbound message with the given id.
+    Outbound(MessageId),
+    /// Reading from the inbound stream with the given id.
+    Inbound(MessageId),
+}
+
+impl TaskId {
+    fn id(self) -> MessageId {
+        match self {
+            Self::Outbound(id) | Self::Inbound(id) => id,
+        }
+    }
+}
+
+/// Events sent from the [`Behaviour`](crate::Behaviour) to a connection handler.
+#[derive(Debug)]
+pub enum HandlerIn<TMsg> {
+    Send(OutboundMessage<TMsg>),
+    Shutdown,
+    SetPriority(ConnectionPriority),
+    UpdateConfig(Box<Config>),
 }
 
 impl<TCodec> Handler<TCodec>
 where
     TCodec: Codec + Send + Clone + 'static,
 {
+    pub(crate) fn update_config(&mut self, config: &Config) {
+        self.substream_timeout = config.substream_timeout;
+        self.idle_connection_grace = config.idle_connection_grace;
+        self.reset_idle_timer();
+        self.io_retry_backoff = config.io_retry_backoff;
+        self.inbound_during_shutdown = config.inbound_during_shutdown;
+        self.decode_diagnostics = config.decode_diagnostics;
+        self.reuse_inbound_stream = config.reuse_inbound_stream;
+        self.write_buffer_size = config.write_buffer_size;
+        self.inbound_read_ahead = config.inbound_read_ahead;
+        self.batch_window = config.batch_window;
+        self.sequence_window = config.max_concurrent_streams;
+        self.inbound_capacity_policy = config.inbound_capacity_policy;
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics = config.metrics.clone();
+        }
+        self.pending_task_limits = Some((config.send_recv_timeout, config.max_concurrent_streams));
+        self.apply_pending_task_limits();
+    }
+
+    /// Whether the handler has no messages to send and no streams in progress.
+    fn is_idle(&self) -> bool {
+        self.tasks.is_empty()
+            && self.pending_outbound.is_empty()
+            && self.requested_outbound.is_empty()
+            && self.delayed_outbound.is_empty()
+            && self.batched_outbound.is_empty()
+            && self.deferred_inbound.is_none()
+    }
+
+    /// The timeout and capacity of `tasks` are fixed, so they can only be replaced while no tasks
+    /// are in progress.
+    fn apply_pending_task_limits(&mut self) {
+        if !self.tasks.is_empty() {
+            return;
+        }
+        if let Some((timeout, capacity)) = self.pending_task_limits.take() {
+            self.tasks = futures_bounded::FuturesMap::new(timeout, capacity);
+            self.task_capacity = capacity;
+        }
+    }
+
+    /// Returns a snapshot of the handler's internal queues.
+    #[cfg(feature = "testing")]
+    pub fn debug_state(&self) -> HandlerDebugState {
+        HandlerDebugState {
+            peer_id: self.peer_id,
+            protocols: self.protocols.clone(),
+            requested_outbound: self.requested_outbound.len(),
+            pending_outbound: self.pending_outbound.len(),
+            pending_events: self.pending_events.len(),
+            tasks: self.tasks.len(),
+        }
+    }
+
+    /// Sets a shared slot that the handler keeps updated with its latest debug state.
+    #[cfg(feature = "testing")]
+    pub(crate) fn set_debug_state_slot(
+        &mut self,
+        slot: std::sync::Arc<std::sync::Mutex<HandlerDebugState>>,
+    ) {
+        self.debug_state = Some(slot);
+        self.update_debug_state();
+    }
+
+    #[cfg(feature = "testing")]
+    fn update_debug_state(&self) {
+        if let Some(slot) = &self.debug_state {
+            *slot.lock().expect("debug state lock poisoned") = self.debug_state();
+        }
+    }
+
+    /// The protocols an outbound substream for `message` is negotiated with, in order of preference.
+    fn outbound_protocols(
+        &self,
+        message: &OutboundMessage<TCodec::Message>,
+    ) -> Vec<StreamProtocol> {
+        match &message.protocol {
+            Some(protocol) => vec![protocol.clone()],
+            None if message.is_request => self.request_protocols.clone(),
+            None => self.protocols.clone(),
+        }
+    }
+
+    /// Returns true if the remote has removed every protocol `message` could be sent with.
+    fn is_unsupported_by_remote(&self, message: &OutboundMessage<TCodec::Message>) -> bool {
+        self.outbound_protocols(message)
+            .iter()
+            .all(|protocol| self.remote_removed_protocols.contains(protocol))
+    }
+
+    fn on_remote_protocols_change(&mut self, change: ProtocolsChange) {
+        match change {
+            ProtocolsChange::Added(added) => {
+                for protocol in added {
+                    self.remote_removed_protocols.remove(protocol);
+                }
+                return;
+            }
+            ProtocolsChange::Removed(removed) => {
+                self.remote_removed_protocols.extend(
+                    removed
+                        .filter(|protocol| {
+                            self.protocols.contains(protocol)
+                                || self.request_protocols.contains(protocol)
+                        })
+                        .cloned(),
+                );
+            }
+        }
+        if self.remote_removed_protocols.is_empty() {
+            return;
+        }
+
+        // Substreams that have already been requested can't be withdrawn, so these messages are
+        // failed now and their substreams discarded once the upgrade completes.
+        let mut failed = Vec::new();
+        for message in &self.requested_outbound {
+            if !self.abandoned_outbound.contains(&message.message_id)
+                && self.is_unsupported_by_remote(message)
+            {
+                failed.push(message.message_id);
+            }
+        }
+        self.abandoned_outbound.extend(failed.iter().copied());
+
+        let pending = std::mem::take(&mut self.pending_outbound);
+        for message in pending {
+            if self.is_unsupported_by_remote(&message) {
+                failed.push(message.message_id);
+            } else {
+                self.pending_outbound.push_back(message);
+            }
+        }
+        let delayed = std::mem::take(&mut self.delayed_outbound);
+        for (retry_at, message) in delayed {
+            if self.is_unsupported_by_remote(&message) {
+                self.retry_attempts.remove(&message.message_id);
+                failed.push(message.message_id);
+            } else {
+                self.delayed_outbound.push((retry_at, message));
+            }
+        }
+
+        // Messages batched with a failed message use the same protocols
+        for message_id in failed.clone() {
+            let batch = self
+                .batched_outbound
+                .remove(&message_id)
+                .unwrap_or_default();
+            failed.extend(batch.into_iter().map(|message| message.message_id));
+        }
+
+        for message_id in failed {
+            tracing::debug!(
+                "failing outbound message {message_id} because the remote no longer supports its protocol"
+            );
+            self.pending_events.push_back(Event::OutboundFailure {
+                peer_id: self.peer_id,
+                message_id,
+                error: Error::ProtocolNotSupported,
+            });
+        }
+    }
+
+    /// Fails every message that hasn't finished sending and every inbound stream still being
+    /// read with [`Error::ConnectionClosed`], since their tasks are dropped with the connection.
+    fn fail_unfinished(&mut self) {
+        // Messages received by inbound tasks that are still in progress
+        while let Ok(event) = self.inbound_event_receiver.try_recv() {
+            self.pending_events.push_back(event);
+        }
+
+        let requested = self
+            .requested_outbound
+            .drain(..)
+            .map(|message| message.message_id)
+            .filter(|message_id| !self.abandoned_outbound.contains(message_id))
+            .collect::<Vec<_>>();
+        let message_ids = self
+            .outbound_in_progress
+            .drain()
+            .flat_map(|(message_id, batch)| std::iter::once(message_id).chain(batch))
+            .chain(requested)
+            .chain(
+                self.batched_outbound
+                    .drain()
+                    .flat_map(|(_, batch)| batch)
+                    .map(|m| m.message_id),
+            )
+            .chain(self.pending_outbound.drain(..).map(|m| m.message_id))
+            .chain(self.delayed_outbound.drain(..).map(|(_, m)| m.message_id))
+            .collect::<Vec<_>>();
+        for message_id in message_ids {
+            self.pending_events.push_back(Event::OutboundFailure {
+                peer_id: self.peer_id,
+                message_id,
+                error: Error::ConnectionClosed,
+            });
+        }
+        let inbound_ids = self
+            .inbound_in_progress
+            .drain()
+            .chain(
+                self.deferred_inbound
+                    .take()
+                    .map(|(task_id, _)| task_id.id()),
+            )
+            .collect::<Vec<_>>();
+        for message_id in inbound_ids {
+            self.pending_events.push_back(Event::InboundFailure {
+                peer_id: self.peer_id,
+                message_id,
+                remote_address: None,
+                error: Error::ConnectionClosed,
+            });
+        }
+    }
+
+    /// Fails the messages batched with the given message.
+    fn fail_batch(&mut self, message_id: MessageId, error: impl Fn() -> Error) {
+        for message in self
+            .batched_outbound
+            .remove(&message_id)
+            .unwrap_or_default()
+        {
+            self.pending_events.push_back(Event::OutboundFailure {
+                peer_id: self.peer_id,
+                message_id: message.message_id,
+                error: error(),
+            });
+        }
+    }
+
+    /// Moves batchable messages from `pending_outbound` into the batch of the given message, which
+    /// is about to request a substream. Messages from an earlier attempt to send the batch are
+    /// kept.
+    fn collect_batch(&mut self, message_id: MessageId) {
+        let batch = self.batched_outbound.entry(message_id).or_default();
+        let mut i = 0;
+        while i < self.pending_outbound.len() && batch.len() + 1 < MAX_BATCH_SIZE {
+            if self.pending_outbound[i].is_batchable() {
+                batch.extend(self.pending_outbound.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        if batch.is_empty() {
+            self.batched_outbound.remove(&message_id);
+        }
+    }
+
+    /// Returns false while the batch window of the message at the front of `pending_outbound` is
+    /// open, so that messages sent shortly after it are written to the same substream.
+    fn poll_batch_window(&mut self, cx: &mut Context<'_>) -> bool {
+        let Some(window) = self.batch_window else {
+            return true;
+        };
+        if !self
+            .pending_outbound
+            .front()
+            .is_some_and(|message| message.is_batchable())
+        {
+            return true;
+        }
+        let timer = self.batch_timer.get_or_insert_with(|| Delay::new(window));
+        if timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        self.batch_timer = None;
+        true
+    }
+
+    fn on_protocol_negotiated(&mut self, protocol: &StreamProtocol) {
+        if self.is_protocol_negotiated {
+            return;
+        }
+        self.is_protocol_negotiated = true;
+        self.pending_events.push_back(Event::ProtocolNegotiated {
+            peer_id: self.peer_id,
+            connection_id: self.connection_id,
+            protocol: protocol.clone(),
+        });
+    }
+
     fn on_listen_upgrade_error(&self, error: ListenUpgradeError<(), Protocol<StreamProtocol>>) {
         tracing::warn!("unexpected listen upgrade error: {:?}", error.error);
     }
 
-    fn on_dial_upgrade_error(&mut self, error: DialUpgradeError<(), Protocol<StreamProtocol>>) {
-        let message = self
+    /// Removes the message a substream was requested for. Substreams may be negotiated in a
+    /// different order than they were requested in.
+    fn take_requested_outbound(
+        &mut self,
+        message_id: MessageId,
+    ) -> OutboundMessage<TCodec::Message> {
+        let ix = self
             .requested_outbound
-            .pop_front()
+            .iter()
+            .position(|message| message.message_id == message_id)
             .expect("negotiated a stream without a pending message");
+        self.requested_outbound
+            .remove(ix)
+            .expect("index is within bounds")
+    }
+
+    fn on_dial_upgrade_error(
+        &mut self,
+        error: DialUpgradeError<MessageId, Protocol<StreamProtocol>>,
+    ) {
+        let message = self.take_requested_outbound(error.info);
+        if self.abandoned_outbound.remove(&message.message_id) {
+            self.retry_attempts.remove(&message.message_id);
+            self.batched_outbound.remove(&message.message_id);
+            return;
+        }
+
+        if !matches!(error.error, StreamUpgradeError::Io(_)) {
+            self.retry_attempts.remove(&message.message_id);
+        }
 
         match error.error {
             StreamUpgradeError::Timeout => {
                 self.pending_events.push_back(Event::OutboundFailure {
                     peer_id: self.peer_id,
                     message_id: message.message_id,
-                    error: Error::DialUpgradeError,
+                    error: Error::Timeout(TimeoutPhase::Negotiation),
+                });
+                self.fail_batch(message.message_id, || {
+                    Error::Timeout(TimeoutPhase::Negotiation)
                 });
             }
             StreamUpgradeError::NegotiationFailed => {
-                // The remote merely doesn't support the protocol(s) we requested.
-                // This is no reason to close the connection, which may
-                // successfully communicate with other protocols already.
-                // An event is reported to permit user code to react to the fact that
-                // the remote peer does not support the requested protocol(s).
+                // Negotiation proposes each requested protocol in order of preference, falling
+                // back to the next one the remote rejects, so this is only reached once all of
+                // them have been rejected. This is no reason to close the connection, which may
+                // still be used with other protocols.
                 self.pending_events.push_back(Event::OutboundFailure {
                     peer_id: self.peer_id,
                     message_id: message.message_id,
                     error: Error::ProtocolNotSupported,
                 });
+                self.fail_batch(message.message_id, || Error::ProtocolNotSupported);
             }
             StreamUpgradeError::Apply(_) => {}
             StreamUpgradeError::Io(e) => {
+                let attempt = self.retry_attempts.entry(message.message_id).or_default();
+                *attempt += 1;
+                let delay = self.io_retry_backoff.delay(*attempt);
                 tracing::debug!(
-                    "outbound stream for request {} failed: {e}, retrying",
+                    "outbound stream for request {} failed: {e}, retrying in {delay:?}",
                     message.message_id
                 );
-                self.requested_outbound.push_back(message);
+                let retry_at = Instant::now() + delay;
+                self.delayed_outbound.push((retry_at, message));
+                if self
+                    .retry_timer
+                    .as_ref()
+                    .is_none_or(|(at, _)| retry_at < *at)
+                {
+                    self.retry_timer = Some((retry_at, Delay::new(delay)));
+                }
             }
         }
     }
 
     fn on_fully_negotiated_outbound(
         &mut self,
-        outbound: FullyNegotiatedOutbound<Protocol<StreamProtocol>, ()>,
+        outbound: FullyNegotiatedOutbound<Protocol<StreamProtocol>, MessageId>,
     ) {
         let mut codec = self.codec.clone();
-        let (mut stream, _protocol) = outbound.protocol;
+        let peer_id = self.peer_id;
+        let (mut stream, protocol) = outbound.protocol;
+        self.on_protocol_negotiated(&protocol);
+        self.shared_state
+            .record_substream_opened(self.connection_id);
 
-        let message = self
-            .requested_outbound
-            .pop_front()
-            .expect("negotiated a stream without a pending message");
+        let message = self.take_requested_outbound(outbound.info);
+        self.retry_attempts.remove(&message.message_id);
+        if self.abandoned_outbound.remove(&message.message_id) {
+            tracing::debug!(
+                "discarding outbound stream for message {} whose protocol was removed by the remote",
+                message.message_id
+            );
+            self.batched_outbound.remove(&message.message_id);
+            return;
+        }
+        let task_id = TaskId::Outbound(message.message_id);
+        let message_id = message.message_id;
+        let is_request = message.is_request;
+        let span = tracing::debug_span!("send_message", %peer_id, message_id = %message.message_id);
+
+        let Some(permit) = self.shared_state.try_acquire_stream() else {
+            tracing::warn!("Dropping outbound stream because the total stream limit was reached");
+            self.pending_events.push_back(Event::OutboundFailure {
+                peer_id,
+                message_id,
+                error: Error::AtCapacity,
+            });
+            self.fail_batch(message_id, || Error::AtCapacity);
+            return;
+        };
+        let batch = self
+            .batched_outbound
+            .remove(&message_id)
+            .unwrap_or_default();
+        let batch_ids = batch.iter().map(|m| m.message_id).collect::<Vec<_>>();
+        let message_ids = std::iter::once(message_id)
+            .chain(batch_ids.iter().copied())
+            .collect::<Vec<_>>();
+        let sequence = self
+            .sequence_gap_detection
+            .then(|| self.outbound_sequence.clone());
+        // Requests are acknowledged by their response
+        let require_ack = self.require_ack && !is_request;
+        let decode_diagnostics = self.decode_diagnostics;
+        let dedup = self.shared_state.dedup_window > 0;
+        let write_buffer_size = self.write_buffer_size;
+        let mut event_sender = self.inbound_event_sender.clone();
 
         let fut = async move {
-            match codec.encode_to(&mut stream, message.message).await {
-                Ok(_) => Event::MessageSent {
-                    message_id: message.message_id,
-                },
-                Err(e) => Event::Error(Error::DecodeError(e)),
+            let _permit = permit;
+            let written = async {
+                let mut sizes = Vec::with_capacity(message_ids.len());
+                // Headers and small messages are coalesced into a single write to the stream
+                let mut writer = BufWriter::with_capacity(write_buffer_size, &mut stream);
+                for message in std::iter::once(message).chain(batch) {
+                    let size =
+                        write_message(&mut codec, &mut writer, message, sequence.as_deref(), dedup)
+                            .await?;
+                    sizes.push(size);
+                }
+                // Send the buffered messages before waiting for a response or acknowledgement
+                writer.flush().await.map_err(Error::WriteError)?;
+                Ok::<_, Error>(sizes)
+            }
+            .await;
+
+            let mut events = match written {
+                Ok(_) if is_request => {
+                    // The responder closes the stream without writing anything if it drops the
+                    // response channel
+                    let mut first_byte = [0u8; 1];
+                    let result = match stream.read(&mut first_byte).await {
+                        Ok(0) => Err(Error::ResponseOmitted),
+                        Ok(_) => {
+                            let mut reader = (&first_byte[..]).chain(&mut stream);
+                            decode_message(&mut codec, &mut reader, decode_diagnostics).await
+                        }
+                        Err(e) => Err(Error::DecodeError(e)),
+                    };
+                    match result {
+                        Ok((response, _)) => vec![Event::Response {
+                            peer_id,
+                            protocol,
+                            request_id: message_id,
+                            message: response,
+                        }],
+                        Err(error) => vec![Event::OutboundFailure {
+                            peer_id,
+                            message_id,
+                            error,
+                        }],
+                    }
+                }
+                Ok(sizes) => {
+                    let result = async {
+                        if require_ack {
+                            for _ in 0..sizes.len() {
+                                let mut ack = [0u8; 1];
+                                stream
+                                    .read_exact(&mut ack)
+                                    .await
+                                    .map_err(Error::DecodeError)?;
+                            }
+                        }
+                        // Close the stream so that the final write is flushed rather than
+                        // truncated when the stream is dropped
+                        stream.close().await.map_err(Error::WriteError)
+                    }
+                    .await;
+                    match result {
+                        Ok(()) => message_ids
+                            .iter()
+                            .zip(sizes)
+                            .map(|(&message_id, size)| {
+                                if require_ack {
+                                    Event::MessageAcked {
+                                        peer_id,
+                                        message_id,
+                                        size,
+                                    }
+                                } else {
+                                    Event::MessageSent {
+                                        peer_id,
+                                        message_id,
+                                        size,
+                                    }
+                                }
+                            })
+                            .collect(),
+                        Err(error) => fail_stream(peer_id, &message_ids, error),
+                    }
+                }
+                Err(error) => fail_stream(peer_id, &message_ids, error),
+            };
+
+            // The task completes with a single event, so the outcomes of the other messages in a
+            // batch are sent through the event channel
+            let last = events.pop();
+            for event in events {
+                if event_sender.send(event).await.is_err() {
+                    return None;
+                }
             }
+            last
         }
+        .instrument(span)
         .boxed();
 
-        if self.tasks.try_push(fut).is_err() {
-            tracing::warn!("Dropping outbound stream because we are at capacity")
+        if self.tasks.try_push(task_id, fut).is_ok() {
+            self.outbound_in_progress.insert(message_id, batch_ids);
+        } else {
+            tracing::warn!("Dropping outbound stream because we are at capacity");
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &self.metrics {
+                metrics.record_outbound_stream_dropped();
+            }
+            for message_id in std::iter::once(message_id).chain(batch_ids) {
+                self.pending_events.push_back(Event::OutboundFailure {
+                    peer_id,
+                    message_id,
+                    error: Error::AtCapacity,
+                });
+            }
         }
     }
 
@@ -122,18 +1197,294 @@ where
     ) {
         let mut codec = self.codec.clone();
         let peer_id = self.peer_id;
-        let (mut stream, _protocol) = inbound.protocol;
+        let (stream, protocol) = inbound.protocol;
+        self.on_protocol_negotiated(&protocol);
+
+        let policy = if self.is_shutting_down {
+            self.inbound_during_shutdown
+        } else {
+            InboundShutdownPolicy::Deliver
+        };
+
+        if policy == InboundShutdownPolicy::Reject {
+            tracing::debug!("Rejecting inbound stream from {peer_id} because we are shutting down");
+            return;
+        }
+
+        let permit = match self.shared_state.try_acquire_inbound_stream(peer_id) {
+            Ok(permit) => permit,
+            Err(error) => {
+                tracing::warn!("Dropping inbound stream from {peer_id}: {error}");
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_inbound_stream_dropped();
+                }
+                if matches!(error, Error::RateLimited { .. }) {
+                    self.pending_events.push_back(Event::InboundFailure {
+                        peer_id,
+                        message_id: self.shared_state.next_inbound_request_id(),
+                        remote_address: None,
+                        error,
+                    });
+                }
+                return;
+            }
+        };
+
+        let inbound_id = self.shared_state.next_inbound_request_id();
+        let request_id = self
+            .request_protocols
+            .contains(&protocol)
+            .then_some(inbound_id);
+        let mut inbound_event_sender = self.inbound_event_sender.clone();
+        let shared_state = self.shared_state.clone();
+        let sequence = self.sequence_gap_detection.then(|| {
+            (
+                self.connection_id,
+                self.inbound_sequence.clone(),
+                self.sequence_window,
+            )
+        });
+        let require_ack = self.require_ack;
+        let decode_diagnostics = self.decode_diagnostics;
+        // Batched messages are read from the same stream
+        let reuse_inbound_stream = self.reuse_inbound_stream || self.batch_window.is_some();
+        let report_stream_closed = self.reuse_inbound_stream;
+        // A stream with a single message has nothing to read ahead of it, and a buffer of 0 reads
+        // from the stream directly
+        let read_ahead = if reuse_inbound_stream {
+            self.inbound_read_ahead
+        } else {
+            0
+        };
+        let dedup = self.shared_state.dedup_window > 0;
+        let inbound_filter = self.inbound_filter.clone();
+
+        let span = tracing::debug_span!("receive_message", %peer_id, %inbound_id);
 
         let fut = async move {
-            match codec.decode_from(&mut stream).await {
-                Ok(message) => Event::ReceivedMessage { peer_id, message },
-                Err(e) => Event::Error(Error::DecodeError(e)),
+            let _permit = permit;
+            let mut stream = BufReader::with_capacity(read_ahead, stream);
+            let mut is_first_message = true;
+            loop {
+                // On a reused stream, the stream ending before the next message is a clean close
+                let mut first_byte = [0u8; 1];
+                let mut prefix: &[u8] = &[];
+                if !is_first_message {
+                    match stream.read(&mut first_byte).await {
+                        Ok(0) => {
+                            return report_stream_closed
+                                .then_some(Event::InboundStreamClosed { peer_id })
+                        }
+                        Ok(_) => prefix = &first_byte,
+                        Err(e) => return Some(Event::Error(Error::DecodeError(e))),
+                    }
+                }
+                is_first_message = false;
+
+                let event = async {
+                    let mut reader = prefix.chain(&mut stream);
+                    if let Some((connection_id, inbound_sequence, window)) = &sequence {
+                        let mut buf = [0u8; 8];
+                        if let Err(e) = reader.read_exact(&mut buf).await {
+                            return Some(Event::Error(Error::DecodeError(e)));
+                        }
+                        let gap = inbound_sequence
+                            .lock()
+                            .expect("sequence lock poisoned")
+                            .record(u64::from_be_bytes(buf), *window);
+                        if let Some((expected, got)) = gap {
+                            let gap = Event::SequenceGap {
+                                peer_id,
+                                connection_id: *connection_id,
+                                expected,
+                                got,
+                            };
+                            if inbound_event_sender.send(gap).await.is_err() {
+                                return None;
+                            }
+                        }
+                    }
+
+                    let mut dedup_key = None;
+                    if dedup {
+                        match read_dedup_key(&mut reader).await {
+                            Ok(key) => dedup_key = key,
+                            Err(e) => return Some(Event::Error(Error::DecodeError(e))),
+                        }
+                    }
+
+                    let decode = decode_message(&mut codec, &mut reader, decode_diagnostics);
+                    let result = AssertUnwindSafe(decode).catch_unwind().await;
+                    let Ok(result) = result else {
+                        tracing::error!("codec panicked while decoding message from {peer_id}");
+                        return Some(Event::Error(Error::CodecPanic { peer_id }));
+                    };
+                    match result {
+                        Ok(_) if policy == InboundShutdownPolicy::Drop => {
+                            tracing::debug!(
+                                "Dropping inbound message from {peer_id} during shutdown"
+                            );
+                            None
+                        }
+                        Ok((message, _))
+                            if inbound_filter
+                                .as_ref()
+                                .is_some_and(|filter| !filter.allows(&peer_id, &message)) =>
+                        {
+                            tracing::debug!("Inbound filter rejected message from {peer_id}");
+                            Some(Event::InboundRejected { peer_id })
+                        }
+                        Ok((message, size)) => {
+                            // The key is only recorded once the message is delivered, so a message
+                            // dropped before then can still be received when it is resent
+                            if let Some(dedup_key) = dedup_key {
+                                if !shared_state.record_dedup_key(peer_id, dedup_key) {
+                                    // The duplicate is still acknowledged, since the original was
+                                    // received
+                                    if require_ack {
+                                        if let Err(e) =
+                                            acknowledge(&mut stream, !reuse_inbound_stream).await
+                                        {
+                                            tracing::debug!(
+                                                "Failed to acknowledge duplicate from {peer_id}: {e}"
+                                            );
+                                        }
+                                    }
+                                    return Some(Event::DuplicateDropped { peer_id, dedup_key });
+                                }
+                            }
+                            let Some(request_id) = request_id else {
+                                if require_ack {
+                                    // The message is still delivered, so the sender may retry a
+                                    // message that was received
+                                    if let Err(e) =
+                                        acknowledge(&mut stream, !reuse_inbound_stream).await
+                                    {
+                                        tracing::debug!(
+                                            "Failed to acknowledge message from {peer_id}: {e}"
+                                        );
+                                    }
+                                }
+                                return Some(Event::ReceivedMessage {
+                                    peer_id,
+                                    protocol: protocol.clone(),
+                                    // Filled in by the behaviour, which tracks the connection's
+                                    // address
+                                    remote_address: None,
+                                    message,
+                                    size,
+                                });
+                            };
+
+                            let (sender, receiver) = oneshot::channel();
+                            let request = Event::InboundRequest {
+                                peer_id,
+                                protocol: protocol.clone(),
+                                request_id,
+                                message,
+                                channel: ResponseChannel { sender },
+                            };
+                            if inbound_event_sender.send(request).await.is_err() {
+                                // The handler has been dropped
+                                return None;
+                            }
+                            // If the channel is dropped without a response, the stream is closed
+                            // so that the requester fails with `Error::ResponseOmitted`
+                            let Ok(response) = receiver.await else {
+                                if let Err(e) = stream.close().await {
+                                    tracing::debug!("Failed to close omitted response stream: {e}");
+                                }
+                                return None;
+                            };
+                            match encode(&mut codec, &mut stream, response).await {
+                                Ok(()) => stream
+                                    .close()
+                                    .await
+                                    .err()
+                                    .map(|e| Event::Error(Error::WriteError(e))),
+                                Err(e) => Some(Event::Error(Error::EncodeError(e))),
+                            }
+                        }
+                        Err(e) => Some(Event::Error(e)),
+                    }
+                }
+                .await;
+
+                // Requests are answered on the stream, so it is never reused
+                if !reuse_inbound_stream || request_id.is_some() {
+                    return event;
+                }
+                let Some(event) = event else {
+                    continue;
+                };
+                // The framing of the stream is lost after an error
+                if matches!(event, Event::Error(_)) {
+                    return Some(event);
+                }
+                if inbound_event_sender.send(event).await.is_err() {
+                    return None;
+                }
             }
         }
+        .instrument(span)
+        .map(move |event| event.map(|event| inbound_failure(peer_id, inbound_id, event)))
         .boxed();
 
-        if self.tasks.try_push(fut).is_err() {
-            tracing::warn!("Dropping inbound stream because we are at capacity")
+        let task_id = TaskId::Inbound(inbound_id);
+        if self.inbound_capacity_policy != CapacityPolicy::EvictOldest {
+            if self.tasks.try_push(task_id, fut).is_ok() {
+                self.inbound_in_progress.insert(inbound_id);
+            } else {
+                self.on_inbound_stream_dropped();
+            }
+            return;
+        }
+
+        let (cancel_sender, cancel_receiver) = oneshot::channel();
+        let fut = future::select(fut, cancel_receiver)
+            .map(move |either| match either {
+                Either::Left((event, _)) => event,
+                Either::Right(_) => Some(inbound_failure(
+                    peer_id,
+                    inbound_id,
+                    Event::Error(Error::Evicted { peer_id }),
+                )),
+            })
+            .boxed();
+        self.inbound_cancellations
+            .retain(|cancel_sender| !cancel_sender.is_canceled());
+        if self.tasks.len() < self.task_capacity {
+            if self.tasks.try_push(task_id, fut).is_ok() {
+                self.inbound_in_progress.insert(inbound_id);
+                self.inbound_cancellations.push_back(cancel_sender);
+            } else {
+                self.on_inbound_stream_dropped();
+            }
+        } else if self.deferred_inbound.is_none() && self.evict_oldest_inbound() {
+            tracing::debug!("Evicted the oldest inbound stream to make room for a new stream");
+            self.inbound_cancellations.push_back(cancel_sender);
+            self.deferred_inbound = Some((task_id, fut));
+        } else {
+            self.on_inbound_stream_dropped();
+        }
+    }
+
+    /// Cancels the oldest inbound task still in progress, returning false if there is none.
+    fn evict_oldest_inbound(&mut self) -> bool {
+        while let Some(cancel_sender) = self.inbound_cancellations.pop_front() {
+            if cancel_sender.send(()).is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn on_inbound_stream_dropped(&self) {
+        tracing::warn!("Dropping inbound stream because we are at capacity");
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record_inbound_stream_dropped();
         }
     }
 }
@@ -142,20 +1493,43 @@ impl<TCodec> ConnectionHandler for Handler<TCodec>
 where
     TCodec: Codec + Send + Clone + 'static,
 {
-    type FromBehaviour = OutboundMessage<TCodec::Message>;
+    type FromBehaviour = HandlerIn<TCodec::Message>;
     type ToBehaviour = Event<TCodec::Message>;
     type InboundProtocol = Protocol<StreamProtocol>;
     type OutboundProtocol = Protocol<StreamProtocol>;
     type InboundOpenInfo = ();
-    type OutboundOpenInfo = ();
+    type OutboundOpenInfo = MessageId;
 
     fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
         SubstreamProtocol::new(
             Protocol {
-                protocol: self.protocol.clone(),
+                protocols: self
+                    .protocols
+                    .iter()
+                    .chain(&self.request_protocols)
+                    .cloned()
+                    .collect(),
             },
             (),
         )
+        .with_timeout(self.substream_timeout)
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        match &self.idle_timer {
+            Some((idle_since, _)) => {
+                idle_since.elapsed() < self.priority.idle_grace(self.idle_connection_grace)
+            }
+            None => true,
+        }
+    }
+
+    fn poll_close(&mut self, _: &mut Context<'_>) -> Poll<Option<Self::ToBehaviour>> {
+        if !self.is_closing {
+            self.is_closing = true;
+            self.fail_unfinished();
+        }
+        Poll::Ready(self.pending_events.pop_front())
     }
 
     fn poll(
@@ -164,17 +1538,80 @@ where
     ) -> Poll<
         ConnectionHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::ToBehaviour>,
     > {
-        match self.tasks.poll_unpin(cx) {
-            Poll::Ready(Ok(event)) => {
-                return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
+        #[cfg(feature = "testing")]
+        self.update_debug_state();
+
+        // Start an inbound task once the task it evicted has completed.
+        if let Some((task_id, fut)) = self.deferred_inbound.take() {
+            if self.tasks.len() >= self.task_capacity {
+                self.deferred_inbound = Some((task_id, fut));
+            } else if self.tasks.try_push(task_id, fut).is_ok() {
+                self.inbound_in_progress.insert(task_id.id());
+            } else {
+                self.on_inbound_stream_dropped();
             }
-            Poll::Ready(Err(err)) => {
-                return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(Event::Error(
-                    Error::Timeout(err),
-                )));
+        }
+
+        // Check for events from inbound tasks, such as requests.
+        if let Poll::Ready(Some(event)) = self.inbound_event_receiver.poll_next_unpin(cx) {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
+        }
+
+        while let Poll::Ready((task_id, result)) = self.tasks.poll_unpin(cx) {
+            let batch = match task_id {
+                TaskId::Outbound(message_id) => self
+                    .outbound_in_progress
+                    .remove(&message_id)
+                    .unwrap_or_default(),
+                TaskId::Inbound(inbound_id) => {
+                    self.inbound_in_progress.remove(&inbound_id);
+                    Vec::new()
+                }
+            };
+            match (task_id, result) {
+                (_, Ok(Some(event))) => {
+                    // Events the task sent before completing, such as a sequence gap, come first
+                    while let Ok(sent) = self.inbound_event_receiver.try_recv() {
+                        self.pending_events.push_back(sent);
+                    }
+                    if self.pending_events.is_empty() {
+                        return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
+                    }
+                    self.pending_events.push_back(event);
+                    let event = self.pending_events.pop_front().expect("checked not empty");
+                    return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
+                }
+                // The task completed without anything to report
+                (_, Ok(None)) => {}
+                (TaskId::Outbound(message_id), Err(_)) => {
+                    for message_id in batch {
+                        self.pending_events.push_back(Event::OutboundFailure {
+                            peer_id: self.peer_id,
+                            message_id,
+                            error: Error::Timeout(TimeoutPhase::Write),
+                        });
+                    }
+                    return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                        Event::OutboundFailure {
+                            peer_id: self.peer_id,
+                            message_id,
+                            error: Error::Timeout(TimeoutPhase::Write),
+                        },
+                    ));
+                }
+                (TaskId::Inbound(message_id), Err(_)) => {
+                    return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                        Event::InboundFailure {
+                            peer_id: self.peer_id,
+                            message_id,
+                            remote_address: None,
+                            error: Error::Timeout(TimeoutPhase::Read),
+                        },
+                    ));
+                }
             }
-            Poll::Pending => {}
         }
+        self.apply_pending_task_limits();
 
         // Drain pending events that were produced by `worker_streams`.
         if let Some(event) = self.pending_events.pop_front() {
@@ -183,24 +1620,51 @@ where
             self.pending_events.shrink_to_fit();
         }
 
-        // // Check for inbound requests.
-        // if let Poll::Ready(Some((id, rq, rs_sender))) = self.inbound_receiver.poll_next_unpin(cx) {
-        //     // We received an inbound request.
-        //
-        //     return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(Event::Request {
-        //         request_id: id,
-        //         request: rq,
-        //         sender: rs_sender,
-        //     }));
-        // }
+        // Move messages whose retry backoff has elapsed back to the outbound queue.
+        if let Some((_, timer)) = self.retry_timer.as_mut() {
+            if timer.poll_unpin(cx).is_ready() {
+                self.retry_timer = None;
+                let now = Instant::now();
+                let mut i = 0;
+                while i < self.delayed_outbound.len() {
+                    if self.delayed_outbound[i].0 <= now {
+                        let (_, message) = self.delayed_outbound.swap_remove(i);
+                        self.enqueue_outbound(message);
+                    } else {
+                        i += 1;
+                    }
+                }
+                if let Some(next_at) = self.delayed_outbound.iter().map(|(at, _)| *at).min() {
+                    let mut timer = Delay::new(next_at.saturating_duration_since(now));
+                    // Register the waker for the next retry
+                    let _ = timer.poll_unpin(cx);
+                    self.retry_timer = Some((next_at, timer));
+                }
+            }
+        }
 
-        // Emit outbound requests.
+        // Emit outbound requests, once the batch window of a batchable message has elapsed
+        if !self.poll_batch_window(cx) {
+            return Poll::Pending;
+        }
         if let Some(message) = self.pending_outbound.pop_front() {
-            let protocol = self.protocol.clone();
+            let _span = tracing::debug_span!(
+                "send_message",
+                peer_id = %self.peer_id,
+                message_id = %message.message_id
+            )
+            .entered();
+            tracing::debug!("requesting outbound substream");
+            let protocols = self.outbound_protocols(&message);
+            if self.batch_window.is_some() && message.is_batchable() {
+                self.collect_batch(message.message_id);
+            }
+            let message_id = message.message_id;
             self.requested_outbound.push_back(message);
 
             return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
-                protocol: SubstreamProtocol::new(Protocol { protocol }, ()),
+                protocol: SubstreamProtocol::new(Protocol { protocols }, message_id)
+                    .with_timeout(self.substream_timeout),
             });
         }
 
@@ -210,11 +1674,29 @@ where
             self.pending_outbound.shrink_to_fit();
         }
 
+        // Wake once the idle grace has elapsed, so that the swarm checks the keep alive again
+        if self.is_idle() {
+            let grace = self.priority.idle_grace(self.idle_connection_grace);
+            let (_, timer) = self
+                .idle_timer
+                .get_or_insert_with(|| (Instant::now(), Delay::new(grace)));
+            let _ = timer.poll_unpin(cx);
+        } else {
+            self.idle_timer = None;
+        }
+
         Poll::Pending
     }
 
-    fn on_behaviour_event(&mut self, msg: Self::FromBehaviour) {
-        self.pending_outbound.push_back(msg);
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        match event {
+            HandlerIn::Send(msg) => self.enqueue_outbound(msg),
+            HandlerIn::Shutdown => self.shutdown(),
+            HandlerIn::SetPriority(priority) => self.set_priority(priority),
+            HandlerIn::UpdateConfig(config) => self.update_config(&config),
+        }
+        #[cfg(feature = "testing")]
+        self.update_debug_state();
     }
 
     fn on_connection_event(
@@ -239,13 +1721,19 @@ where
             ConnectionEvent::ListenUpgradeError(listen_upgrade_error) => {
                 self.on_listen_upgrade_error(listen_upgrade_error)
             }
+            ConnectionEvent::RemoteProtocolsChange(change) => {
+                self.on_remote_protocols_change(change)
+            }
+            // The protocols we listen on are fixed by the config, so local changes concern other
+            // handlers
+            ConnectionEvent::LocalProtocolsChange(_) => {}
             _ => {}
         }
     }
 }
 
 pub struct Protocol<P> {
-    pub(crate) protocol: P,
+    pub(crate) protocols: Vec<P>,
 }
 
 impl<P> UpgradeInfo for Protocol<P>
@@ -253,10 +1741,10 @@ where
     P: AsRef<str> + Clone,
 {
     type Info = P;
-    type InfoIter = std::option::IntoIter<Self::Info>;
+    type InfoIter = std::vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        Some(self.protocol.clone()).into_iter()
+        self.protocols.clone().into_iter()
     }
 }
 
@@ -4,10 +4,28 @@ mod config;
 pub mod error;
 mod event;
 mod handler;
+mod loopback;
 mod message;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod protocol;
+mod stats;
+#[cfg(feature = "message-store")]
+mod store;
+#[cfg(feature = "testing")]
+pub mod testing;
 
 pub use behaviour::*;
 pub use codec::*;
 pub use config::*;
 pub use event::*;
+#[cfg(feature = "testing")]
+pub use handler::HandlerDebugState;
+pub use loopback::*;
 pub use message::*;
+#[cfg(feature = "metrics")]
+pub use metrics::*;
+pub use protocol::*;
+pub use stats::*;
+#[cfg(feature = "message-store")]
+pub use store::*;
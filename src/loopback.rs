@@ -0,0 +1,151 @@
+use crate::codec::Codec;
+use crate::error::Error;
+use crate::event::Event;
+use crate::{MessageId, EMPTY_QUEUE_SHRINK_THRESHOLD};
+use libp2p::core::Endpoint;
+use libp2p::futures::future::BoxFuture;
+use libp2p::futures::stream::FuturesUnordered;
+use libp2p::futures::{FutureExt, StreamExt};
+use libp2p::swarm::{
+    dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+    THandlerOutEvent, ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId, StreamProtocol};
+use std::collections::VecDeque;
+use std::task::{Context, Poll};
+
+/// A behaviour for testing application message handling without a transport. Every message sent is
+/// encoded and decoded with the codec and delivered back as an [`Event::ReceivedMessage`] from the
+/// peer it was sent to.
+pub struct LoopbackBehaviour<TCodec>
+where
+    TCodec: Codec + Send + Clone + 'static,
+{
+    protocol: StreamProtocol,
+    codec: TCodec,
+    pending_events: VecDeque<Event<TCodec::Message>>,
+    tasks: FuturesUnordered<BoxFuture<'static, Vec<Event<TCodec::Message>>>>,
+    next_outbound_message_id: MessageId,
+}
+
+impl<TCodec> LoopbackBehaviour<TCodec>
+where
+    TCodec: Codec + Send + Clone + 'static,
+{
+    pub fn new(protocol: StreamProtocol) -> Self
+    where
+        TCodec: Default,
+    {
+        Self::with_codec(protocol, TCodec::default())
+    }
+
+    /// Creates a loopback behaviour that uses the given codec instead of the codec's default.
+    pub fn with_codec(protocol: StreamProtocol, codec: TCodec) -> Self {
+        Self {
+            protocol,
+            codec,
+            pending_events: VecDeque::new(),
+            tasks: FuturesUnordered::new(),
+            next_outbound_message_id: MessageId::default(),
+        }
+    }
+
+    /// Loops the message back through the codec, returning the id assigned to the message.
+    pub fn send_message(&mut self, peer_id: PeerId, message: TCodec::Message) -> MessageId {
+        let message_id = self.next_outbound_message_id;
+        self.next_outbound_message_id = self.next_outbound_message_id.wrapping_next();
+
+        let mut codec = self.codec.clone();
+        let protocol = self.protocol.clone();
+        let fut = async move {
+            let mut buf = Vec::new();
+            if let Err(e) = codec.encode_to(&mut buf, message).await {
+                return vec![Event::OutboundFailure {
+                    peer_id,
+                    message_id,
+                    error: Error::EncodeError(e),
+                }];
+            }
+
+            let size = buf.len();
+            let sent = Event::MessageSent {
+                peer_id,
+                message_id,
+                size,
+            };
+            match codec.decode_from(&mut buf.as_slice()).await {
+                Ok(message) => vec![
+                    sent,
+                    Event::ReceivedMessage {
+                        peer_id,
+                        protocol,
+                        remote_address: None,
+                        message,
+                        size,
+                    },
+                ],
+                Err(e) => vec![sent, Event::Error(Error::DecodeError(e))],
+            }
+        }
+        .boxed();
+        self.tasks.push(fut);
+
+        message_id
+    }
+}
+
+impl<TCodec> NetworkBehaviour for LoopbackBehaviour<TCodec>
+where
+    TCodec: Codec + Send + Clone + 'static,
+{
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Event<TCodec::Message>;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _local_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _addr: &Multiaddr,
+        _role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, _event: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        while let Poll::Ready(Some(events)) = self.tasks.poll_next_unpin(cx) {
+            self.pending_events.extend(events);
+        }
+
+        if let Some(event) = self.pending_events.pop_front() {
+            return Poll::Ready(ToSwarm::GenerateEvent(event));
+        } else if self.pending_events.capacity() > EMPTY_QUEUE_SHRINK_THRESHOLD {
+            self.pending_events.shrink_to_fit();
+        }
+
+        Poll::Pending
+    }
+}
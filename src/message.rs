@@ -1,10 +1,143 @@
-use libp2p::PeerId;
+use libp2p::futures::channel::oneshot;
+use libp2p::{PeerId, StreamProtocol};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub type MessageId = u64;
+/// Identifies an outbound message, as returned by
+/// [`Behaviour::send_message`](crate::Behaviour::send_message).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageId(u64);
+
+impl MessageId {
+    pub const fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    /// Returns the id as an integer.
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn wrapping_next(self) -> Self {
+        Self(self.0.wrapping_add(1))
+    }
+
+    pub(crate) fn as_usize(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Requests are identified by the id of the outbound message that carries them.
+pub type RequestId = MessageId;
+
+/// The priority of messages sent without an explicit priority.
+pub const DEFAULT_PRIORITY: u8 = 0;
+
+/// The maximum number of messages written to a single substream if
+/// [`Config::batch_window`](crate::Config::batch_window) is set.
+pub const MAX_BATCH_SIZE: usize = 64;
 
 #[derive(Debug, Clone)]
 pub struct OutboundMessage<TMsg> {
     pub peer_id: PeerId,
+    /// The protocol used to open the outbound substream for this message. If `None`, any supported
+    /// protocol may be negotiated, in order of preference.
+    pub protocol: Option<StreamProtocol>,
     pub message: TMsg,
     pub message_id: MessageId,
+    /// Whether a response is expected on the same substream.
+    pub is_request: bool,
+    /// A key that is stable across application retries, used by the receiver to drop duplicates.
+    pub dedup_key: Option<u64>,
+    /// Messages with a higher priority are sent before queued messages with a lower priority.
+    pub priority: u8,
+    /// When the message was sent by the application.
+    pub queued_at: Instant,
+    /// How long after `queued_at` the message may still be handed to a connection, if limited.
+    pub ttl: Option<Duration>,
+}
+
+impl<TMsg> OutboundMessage<TMsg> {
+    /// Returns true if the message may share a substream with other messages.
+    pub(crate) fn is_batchable(&self) -> bool {
+        !self.is_request && self.protocol.is_none()
+    }
+
+    /// Returns true if the message has outlived its time to live.
+    pub(crate) fn is_expired(&self, now: Instant) -> bool {
+        self.ttl
+            .is_some_and(|ttl| now.saturating_duration_since(self.queued_at) > ttl)
+    }
+}
+
+/// Returns the position in the queue at which a message with the given priority is inserted, so
+/// that the queue is ordered by descending priority and FIFO within a priority. Returns `None` if
+/// the message belongs at the back.
+pub(crate) fn priority_position<'a, TMsg: 'a>(
+    queue: impl IntoIterator<Item = &'a OutboundMessage<TMsg>>,
+    priority: u8,
+) -> Option<usize> {
+    queue
+        .into_iter()
+        .position(|queued| queued.priority < priority)
+}
+
+/// Used to reply to an [`Event::InboundRequest`](crate::Event::InboundRequest). The response is
+/// written to the substream the request was received on. If the channel is dropped without a
+/// response, the request fails on the requester with
+/// [`Error::ResponseOmitted`](crate::error::Error::ResponseOmitted).
+pub struct ResponseChannel<TMsg> {
+    pub(crate) sender: oneshot::Sender<TMsg>,
+}
+
+impl<TMsg> ResponseChannel<TMsg> {
+    /// Sends the response. If the substream has already been closed, for example because the
+    /// request timed out, the response is returned as an error.
+    pub fn send(self, response: TMsg) -> Result<(), TMsg> {
+        self.sender.send(response)
+    }
+}
+
+impl<TMsg> fmt::Debug for ResponseChannel<TMsg> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseChannel").finish()
+    }
+}
+
+/// Decides whether an inbound message is delivered, set with
+/// [`Behaviour::with_inbound_filter`](crate::Behaviour::with_inbound_filter).
+pub(crate) struct InboundFilter<TMsg>(Arc<FilterFn<TMsg>>);
+
+type FilterFn<TMsg> = dyn Fn(&PeerId, &TMsg) -> bool + Send + Sync;
+
+impl<TMsg> InboundFilter<TMsg> {
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&PeerId, &TMsg) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(filter))
+    }
+
+    pub fn allows(&self, peer_id: &PeerId, message: &TMsg) -> bool {
+        (self.0)(peer_id, message)
+    }
+}
+
+impl<TMsg> Clone for InboundFilter<TMsg> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<TMsg> fmt::Debug for InboundFilter<TMsg> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InboundFilter").finish()
+    }
 }
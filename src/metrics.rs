@@ -0,0 +1,178 @@
+use crate::error::Error;
+use crate::event::Event;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::registry::Registry;
+
+/// Counters for messages and failures. Cloning the metrics shares the underlying counters.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    messages_sent: Counter,
+    messages_received: Counter,
+    outbound_failures: Family<ErrorLabels, Counter>,
+    inbound_failures: Family<ErrorLabels, Counter>,
+    errors: Family<ErrorLabels, Counter>,
+    streams_dropped: Family<DirectionLabels, Counter>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ErrorLabels {
+    kind: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DirectionLabels {
+    direction: String,
+}
+
+impl Metrics {
+    /// Creates the metrics and registers them with the registry.
+    pub fn new(registry: &mut Registry) -> Self {
+        let sub_registry = registry.sub_registry_with_prefix("messaging");
+
+        let messages_sent = Counter::default();
+        sub_registry.register(
+            "messages_sent",
+            "Number of messages successfully sent",
+            messages_sent.clone(),
+        );
+
+        let messages_received = Counter::default();
+        sub_registry.register(
+            "messages_received",
+            "Number of messages received",
+            messages_received.clone(),
+        );
+
+        let outbound_failures = Family::default();
+        sub_registry.register(
+            "outbound_failures",
+            "Number of outbound messages that failed, by error kind",
+            outbound_failures.clone(),
+        );
+
+        let inbound_failures = Family::default();
+        sub_registry.register(
+            "inbound_failures",
+            "Number of inbound failures, by error kind",
+            inbound_failures.clone(),
+        );
+
+        let errors = Family::default();
+        sub_registry.register(
+            "errors",
+            "Number of errors not attributed to a message, by error kind",
+            errors.clone(),
+        );
+
+        let streams_dropped = Family::default();
+        sub_registry.register(
+            "streams_dropped",
+            "Number of streams dropped because the stream limit was reached, by direction",
+            streams_dropped.clone(),
+        );
+
+        Self {
+            messages_sent,
+            messages_received,
+            outbound_failures,
+            inbound_failures,
+            errors,
+            streams_dropped,
+        }
+    }
+
+    pub(crate) fn record<TMsg>(&self, event: &Event<TMsg>) {
+        match event {
+            Event::ReceivedMessage { .. } => {
+                self.messages_received.inc();
+            }
+            Event::MessageSent { .. } | Event::MessageAcked { .. } => {
+                self.messages_sent.inc();
+            }
+            Event::InboundRequest { .. } => {
+                self.messages_received.inc();
+            }
+            Event::Response { .. } => {
+                self.messages_sent.inc();
+                self.messages_received.inc();
+            }
+            Event::InboundFailure { error, .. } => {
+                self.inbound_failures
+                    .get_or_create(&ErrorLabels::new(error))
+                    .inc();
+            }
+            Event::BulkFailure {
+                message_ids, error, ..
+            } => {
+                self.outbound_failures
+                    .get_or_create(&ErrorLabels::new(error))
+                    .inc_by(message_ids.len() as u64);
+            }
+            Event::OutboundFailure { error, .. } => {
+                self.outbound_failures
+                    .get_or_create(&ErrorLabels::new(error))
+                    .inc();
+            }
+            Event::Error(error) => {
+                self.errors.get_or_create(&ErrorLabels::new(error)).inc();
+            }
+            Event::PeerConnected { .. }
+            | Event::ProtocolNegotiated { .. }
+            | Event::PeerDisconnected { .. }
+            | Event::SequenceGap { .. }
+            | Event::DuplicateDropped { .. }
+            | Event::InboundRejected { .. }
+            | Event::OutboundQueued { .. }
+            | Event::InboundStreamClosed { .. }
+            | Event::MessageCancelled { .. }
+            | Event::PeerFlushed { .. } => {}
+        }
+    }
+
+    pub(crate) fn record_inbound_stream_dropped(&self) {
+        self.streams_dropped
+            .get_or_create(&DirectionLabels {
+                direction: "inbound".to_string(),
+            })
+            .inc();
+    }
+
+    pub(crate) fn record_outbound_stream_dropped(&self) {
+        self.streams_dropped
+            .get_or_create(&DirectionLabels {
+                direction: "outbound".to_string(),
+            })
+            .inc();
+    }
+}
+
+impl ErrorLabels {
+    fn new(error: &Error) -> Self {
+        let kind = match error {
+            Error::DecodeError(_) | Error::CodecError { .. } => "decode_error".to_string(),
+            Error::EncodeError(_) => "encode_error".to_string(),
+            Error::WriteError(_) => "write_error".to_string(),
+            Error::ConnectionClosed => "connection_closed".to_string(),
+            Error::Timeout(phase) => format!("timeout_{phase}"),
+            Error::DialFailure => "dial_failure".to_string(),
+            Error::DialTimeout => "dial_timeout".to_string(),
+            Error::SelfDial => "self_dial".to_string(),
+            Error::DialUpgradeError => "dial_upgrade_error".to_string(),
+            Error::ProtocolNotSupported => "protocol_not_supported".to_string(),
+            Error::ResponseOmitted => "response_omitted".to_string(),
+            Error::Expired => "expired".to_string(),
+            Error::QueueFull => "queue_full".to_string(),
+            Error::AllConnectionsSaturated => "all_connections_saturated".to_string(),
+            Error::MessageTooLarge { .. } => "message_too_large".to_string(),
+            Error::AtCapacity => "at_capacity".to_string(),
+            Error::RateLimited { .. } => "rate_limited".to_string(),
+            Error::Evicted { .. } => "evicted".to_string(),
+            Error::CodecPanic { .. } => "codec_panic".to_string(),
+            #[cfg(feature = "message-store")]
+            Error::MessageStore(_) => "message_store".to_string(),
+        };
+        Self { kind }
+    }
+}
@@ -0,0 +1,23 @@
+use crate::error::ConfigError;
+use libp2p::StreamProtocol;
+
+/// Returns the protocol `/{name}/{version}`, for example `/myapp/1.0.0`.
+///
+/// Returns [`ConfigError::InvalidProtocol`] if either part is empty or contains a `/` or
+/// whitespace.
+///
+/// ```
+/// let protocol = libp2p_messaging::protocol("myapp", "1.0.0").unwrap();
+/// assert_eq!(protocol.as_ref(), "/myapp/1.0.0");
+/// assert!(libp2p_messaging::protocol("my/app", "1.0.0").is_err());
+/// ```
+pub fn protocol(name: &str, version: &str) -> Result<StreamProtocol, ConfigError> {
+    let protocol = format!("/{name}/{version}");
+    let is_valid_part =
+        |part: &str| !part.is_empty() && !part.contains(|c: char| c == '/' || c.is_whitespace());
+    if !is_valid_part(name) || !is_valid_part(version) {
+        return Err(ConfigError::InvalidProtocol(protocol));
+    }
+    StreamProtocol::try_from_owned(protocol.clone())
+        .map_err(|_| ConfigError::InvalidProtocol(protocol))
+}
@@ -0,0 +1,65 @@
+use libp2p::swarm::ConnectionId;
+
+/// A snapshot of the messaging state for a peer.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStats {
+    /// The number of messages queued while waiting for a connection to the peer.
+    pub num_queued: usize,
+    /// Statistics for each established connection to the peer.
+    pub connections: Vec<ConnectionStats>,
+}
+
+impl PeerStats {
+    /// The total number of messages to the peer that have not yet been sent or failed.
+    pub fn num_pending(&self) -> usize {
+        self.num_queued
+            + self
+                .connections
+                .iter()
+                .map(|c| c.num_pending)
+                .sum::<usize>()
+    }
+
+    /// The average number of messages sent per outbound substream across all connections to
+    /// the peer, or `None` if no substreams have been opened.
+    pub fn reuse_factor(&self) -> Option<f64> {
+        let substreams = self
+            .connections
+            .iter()
+            .map(|c| c.substreams_opened)
+            .sum::<u64>();
+        if substreams == 0 {
+            return None;
+        }
+        let sent = self
+            .connections
+            .iter()
+            .map(|c| c.messages_sent)
+            .sum::<u64>();
+        Some(sent as f64 / substreams as f64)
+    }
+}
+
+/// Statistics for a single connection to a peer.
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub connection_id: ConnectionId,
+    /// The number of messages dispatched to the connection that have not yet been sent or failed.
+    pub num_pending: usize,
+    /// The number of messages successfully sent on the connection.
+    pub messages_sent: u64,
+    /// The number of outbound substreams opened on the connection.
+    pub substreams_opened: u64,
+}
+
+impl ConnectionStats {
+    /// The average number of messages sent per outbound substream on this connection, or `None` if
+    /// no substreams have been opened. A value close to 1.0 indicates substreams are not being
+    /// reused.
+    pub fn reuse_factor(&self) -> Option<f64> {
+        if self.substreams_opened == 0 {
+            return None;
+        }
+        Some(self.messages_sent as f64 / self.substreams_opened as f64)
+    }
+}
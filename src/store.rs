@@ -0,0 +1,20 @@
+use crate::{MessageId, OutboundMessage};
+use libp2p::PeerId;
+use std::{fmt, io};
+
+/// Durable storage for outbound messages that overflow the in-memory queue of a peer that is not
+/// connected. Persisted messages are loaded and sent once a connection to the peer is established.
+///
+/// Message ids are allocated by the [`Behaviour`](crate::Behaviour) and are not guaranteed to be
+/// unique across restarts, so implementations should not rely on them as durable keys.
+pub trait MessageStore<TMsg>: fmt::Debug + Send {
+    /// Persists a message that could not be queued in memory.
+    fn persist(&mut self, message: OutboundMessage<TMsg>) -> io::Result<()>;
+
+    /// Loads all persisted messages for the peer. Loaded messages remain in the store until they
+    /// are removed.
+    fn load(&mut self, peer_id: &PeerId) -> io::Result<Vec<OutboundMessage<TMsg>>>;
+
+    /// Removes a persisted message once it has been sent.
+    fn remove(&mut self, peer_id: &PeerId, message_id: MessageId) -> io::Result<()>;
+}
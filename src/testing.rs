@@ -0,0 +1,99 @@
+//! Helpers for exercising a [`Codec`] without a network connection.
+
+use crate::Codec;
+use libp2p::futures::{AsyncRead, AsyncWrite};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Returns a pair of connected in-memory streams. Bytes written to one stream are read from the
+/// other. Closing a stream ends the other stream's reads once the buffered bytes are consumed.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let a_to_b = Arc::new(Mutex::new(Pipe::default()));
+    let b_to_a = Arc::new(Mutex::new(Pipe::default()));
+    let a = DuplexStream {
+        read: b_to_a.clone(),
+        write: a_to_b.clone(),
+    };
+    let b = DuplexStream {
+        read: a_to_b,
+        write: b_to_a,
+    };
+    (a, b)
+}
+
+/// Encodes the message and decodes it again using the codec, returning the decoded message.
+pub async fn roundtrip<C: Codec>(codec: &mut C, message: C::Message) -> io::Result<C::Message> {
+    let (mut writer, mut reader) = duplex();
+    codec.encode_to(&mut writer, message).await?;
+    codec.decode_from(&mut reader).await
+}
+
+/// One end of an in-memory stream created by [`duplex`].
+#[derive(Debug)]
+pub struct DuplexStream {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+#[derive(Debug, Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    is_closed: bool,
+    read_waker: Option<Waker>,
+}
+
+impl AsyncRead for DuplexStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.read.lock().expect("pipe lock poisoned");
+        if pipe.buf.is_empty() {
+            if pipe.is_closed {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dst = src;
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for DuplexStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.write.lock().expect("pipe lock poisoned");
+        if pipe.is_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        pipe.buf.extend(buf);
+        if let Some(waker) = pipe.read_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pipe = self.write.lock().expect("pipe lock poisoned");
+        pipe.is_closed = true;
+        if let Some(waker) = pipe.read_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,252 @@
+#[cfg(feature = "bytes")]
+#[test]
+fn bytes_codec_reports_u32_framing() {
+    use libp2p_messaging::{Codec, Framing, LengthPrefix};
+
+    let codec = libp2p_messaging::bytes::BytesCodec;
+    assert_eq!(
+        codec.framing(),
+        Framing {
+            prefix: LengthPrefix::U32BigEndian,
+            max_message_size: Some(4 * 1024 * 1024),
+        }
+    );
+}
+
+#[cfg(feature = "prost")]
+#[test]
+fn prost_codec_reports_u32_framing() {
+    use libp2p_messaging::{Codec, Framing, LengthPrefix};
+
+    let codec = libp2p_messaging::prost::ProstCodec::<()>::default();
+    assert_eq!(
+        codec.framing(),
+        Framing {
+            prefix: LengthPrefix::U32BigEndian,
+            max_message_size: Some(4 * 1024 * 1024),
+        }
+    );
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn eof_bytes_codec_reports_eof_framing() {
+    use libp2p_messaging::{Codec, Framing, LengthPrefix};
+
+    let codec = libp2p_messaging::bytes::EofBytesCodec;
+    assert_eq!(
+        codec.framing(),
+        Framing {
+            prefix: LengthPrefix::Eof,
+            max_message_size: Some(4 * 1024 * 1024),
+        }
+    );
+}
+
+#[cfg(all(feature = "bytes", feature = "testing"))]
+#[test]
+fn bytes_codec_round_trips_messages() {
+    use libp2p::futures::executor::block_on;
+    use libp2p_messaging::bytes::BytesCodec;
+    use libp2p_messaging::testing::roundtrip;
+
+    for size in [0, 1, 1024, 64 * 1024] {
+        let message = bytes::Bytes::from(vec![0xab; size]);
+        let decoded = block_on(roundtrip(&mut BytesCodec, message.clone())).expect("round trip");
+        assert_eq!(decoded, message);
+    }
+}
+
+#[cfg(all(feature = "bytes", feature = "testing"))]
+#[test]
+fn bytes_codec_decodes_consecutive_messages_from_one_stream() {
+    use libp2p::futures::executor::block_on;
+    use libp2p_messaging::bytes::BytesCodec;
+    use libp2p_messaging::testing::duplex;
+    use libp2p_messaging::Codec;
+
+    let (mut writer, mut reader) = duplex();
+    let messages = [&b"first"[..], b"", b"third"].map(bytes::Bytes::from_static);
+    for message in &messages {
+        block_on(BytesCodec.encode_to(&mut writer, message.clone())).expect("encode message");
+    }
+    for message in messages {
+        let decoded = block_on(BytesCodec.decode_from(&mut reader)).expect("decode message");
+        assert_eq!(decoded, message);
+    }
+}
+
+#[cfg(all(feature = "bytes", feature = "testing"))]
+#[test]
+fn eof_bytes_codec_reads_message_until_writer_closes() {
+    use libp2p::futures::executor::block_on;
+    use libp2p::futures::io::AsyncWriteExt;
+    use libp2p_messaging::testing::duplex;
+    use libp2p_messaging::Codec;
+
+    let mut codec = libp2p_messaging::bytes::EofBytesCodec;
+    let message = bytes::Bytes::from_static(b"a message without a length prefix");
+
+    let (mut writer, mut reader) = duplex();
+    block_on(async {
+        codec.encode_to(&mut writer, message.clone()).await?;
+        writer.close().await
+    })
+    .expect("encode message");
+
+    let decoded = block_on(codec.decode_from(&mut reader)).expect("decode message");
+    assert_eq!(decoded, message);
+}
+
+#[cfg(all(feature = "bytes", feature = "testing"))]
+#[test]
+fn map_codec_round_trips_outer_message() {
+    use libp2p::futures::executor::block_on;
+    use libp2p_messaging::bytes::BytesCodec;
+    use libp2p_messaging::testing::{duplex, roundtrip};
+    use libp2p_messaging::{Codec, MapCodec};
+
+    #[derive(Debug, PartialEq)]
+    struct Greeting(String);
+
+    impl From<Greeting> for bytes::Bytes {
+        fn from(greeting: Greeting) -> Self {
+            greeting.0.into()
+        }
+    }
+
+    impl TryFrom<bytes::Bytes> for Greeting {
+        type Error = std::string::FromUtf8Error;
+
+        fn try_from(bytes: bytes::Bytes) -> Result<Self, Self::Error> {
+            String::from_utf8(bytes.to_vec()).map(Greeting)
+        }
+    }
+
+    let mut codec = MapCodec::<BytesCodec, Greeting>::default();
+
+    // The wire format is that of the inner codec
+    let (mut writer, mut reader) = duplex();
+    block_on(codec.encode_to(&mut writer, Greeting("hello".to_string()))).expect("encode message");
+    let inner = block_on(BytesCodec.decode_from(&mut reader)).expect("decode inner message");
+    assert_eq!(inner, "hello".as_bytes());
+
+    let decoded =
+        block_on(roundtrip(&mut codec, Greeting("hello".to_string()))).expect("round trip");
+    assert_eq!(decoded, Greeting("hello".to_string()));
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn bytes_codec_estimates_the_encoded_size() {
+    use libp2p::futures::executor::block_on;
+    use libp2p::futures::io::Cursor;
+    use libp2p_messaging::bytes::{BytesCodec, EofBytesCodec};
+    use libp2p_messaging::Codec;
+
+    let message = bytes::Bytes::from(vec![0xab; 100]);
+    let mut writer = Cursor::new(Vec::new());
+    block_on(BytesCodec.encode_to(&mut writer, message.clone())).expect("encode message");
+    assert_eq!(
+        BytesCodec.estimate_size(&message),
+        Some(writer.into_inner().len())
+    );
+
+    let mut writer = Cursor::new(Vec::new());
+    block_on(EofBytesCodec.encode_to(&mut writer, message.clone())).expect("encode message");
+    assert_eq!(
+        EofBytesCodec.estimate_size(&message),
+        Some(writer.into_inner().len())
+    );
+}
+
+#[test]
+fn default_encode_to_bytes_strips_the_length_prefix() {
+    use async_trait::async_trait;
+    use libp2p::futures::executor::block_on;
+    use libp2p::futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+    use libp2p_messaging::{Codec, Framing, LengthPrefix};
+    use std::io;
+
+    #[derive(Debug, Default)]
+    struct PrefixedCodec;
+
+    #[async_trait]
+    impl Codec for PrefixedCodec {
+        type Message = Vec<u8>;
+
+        async fn decode_from<R>(&mut self, _reader: &mut R) -> io::Result<Self::Message>
+        where
+            R: AsyncRead + Unpin + Send,
+        {
+            unimplemented!("only encodes")
+        }
+
+        async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+        where
+            W: AsyncWrite + Unpin + Send,
+        {
+            writer
+                .write_all(&(message.len() as u32).to_be_bytes())
+                .await?;
+            writer.write_all(&message).await
+        }
+
+        fn framing(&self) -> Framing {
+            Framing {
+                prefix: LengthPrefix::U32BigEndian,
+                max_message_size: None,
+            }
+        }
+    }
+
+    let body = block_on(PrefixedCodec.encode_to_bytes(b"hello".to_vec())).expect("encode message");
+    assert_eq!(body, "hello".as_bytes());
+    assert!(!PrefixedCodec.prefers_bytes());
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn bytes_codec_encodes_to_bytes_without_copying() {
+    use libp2p::futures::executor::block_on;
+    use libp2p_messaging::bytes::BytesCodec;
+    use libp2p_messaging::Codec;
+
+    let message = bytes::Bytes::from(vec![0xab; 100]);
+    let body = block_on(BytesCodec.encode_to_bytes(message.clone())).expect("encode message");
+    assert_eq!(body.as_ptr(), message.as_ptr());
+    assert_eq!(body.len(), message.len());
+    assert!(BytesCodec.prefers_bytes());
+
+    let oversized = bytes::Bytes::from(vec![0; 4 * 1024 * 1024 + 1]);
+    let error = block_on(BytesCodec.encode_to_bytes(oversized)).expect_err("message too large");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(all(feature = "bytes", feature = "testing"))]
+#[test]
+fn map_codec_from_fns_maps_foreign_messages() {
+    use libp2p::futures::executor::block_on;
+    use libp2p_messaging::bytes::BytesCodec;
+    use libp2p_messaging::testing::roundtrip;
+    use libp2p_messaging::{Codec, MapCodec};
+    use std::net::Ipv4Addr;
+
+    let mut codec = MapCodec::from_fns(
+        BytesCodec,
+        |address: &Ipv4Addr| bytes::Bytes::copy_from_slice(&address.octets()),
+        |bytes: bytes::Bytes| {
+            <[u8; 4]>::try_from(bytes.as_ref())
+                .map(Ipv4Addr::from)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        },
+    );
+
+    let decoded = block_on(roundtrip(&mut codec, Ipv4Addr::LOCALHOST)).expect("round trip");
+    assert_eq!(decoded, Ipv4Addr::LOCALHOST);
+
+    // Sizing and framing are those of the inner codec
+    assert_eq!(codec.estimate_size(&Ipv4Addr::LOCALHOST), Some(4 + 4));
+    assert_eq!(codec.framing(), BytesCodec.framing());
+    assert!(codec.prefers_bytes());
+}
@@ -0,0 +1,43 @@
+use libp2p_messaging::error::ConfigError;
+use libp2p_messaging::Config;
+use std::time::Duration;
+
+#[test]
+fn builder_sets_the_given_fields() {
+    let config = Config::builder()
+        .max_concurrent_streams(4)
+        .send_recv_timeout(Duration::from_secs(3))
+        .build()
+        .expect("valid config");
+    assert_eq!(config.max_concurrent_streams, 4);
+    assert_eq!(config.send_recv_timeout, Duration::from_secs(3));
+
+    // Fields that aren't set keep their defaults
+    let default = Config::default();
+    assert_eq!(
+        config.max_pending_outbound_per_peer,
+        default.max_pending_outbound_per_peer
+    );
+}
+
+#[test]
+fn builder_rejects_zero_stream_limits() {
+    assert_eq!(
+        Config::builder().max_concurrent_streams(0).build().err(),
+        Some(ConfigError::ZeroMaxConcurrentStreams)
+    );
+    assert_eq!(
+        Config::builder()
+            .max_total_concurrent_streams(0)
+            .build()
+            .err(),
+        Some(ConfigError::ZeroMaxTotalConcurrentStreams)
+    );
+    assert_eq!(
+        Config::builder()
+            .max_inbound_streams_per_peer(0)
+            .build()
+            .err(),
+        Some(ConfigError::ZeroMaxInboundStreamsPerPeer)
+    );
+}
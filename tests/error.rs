@@ -0,0 +1,27 @@
+use libp2p_messaging::error::Error;
+use std::io;
+
+fn read_frame(result: io::Result<Vec<u8>>) -> Result<Vec<u8>, Error> {
+    Ok(result?)
+}
+
+#[test]
+fn io_error_converts_to_decode_error() {
+    let error = Error::from(io::Error::new(io::ErrorKind::InvalidData, "bad frame"));
+    let Error::DecodeError(io_error) = error else {
+        panic!("unexpected error: {error}");
+    };
+    assert_eq!(io_error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(io_error.to_string(), "bad frame");
+}
+
+#[test]
+fn io_error_propagates_with_question_mark() {
+    assert_eq!(read_frame(Ok(b"frame".to_vec())).expect("frame"), b"frame");
+
+    let error = read_frame(Err(io::ErrorKind::UnexpectedEof.into())).unwrap_err();
+    assert!(
+        matches!(&error, Error::DecodeError(e) if e.kind() == io::ErrorKind::UnexpectedEof),
+        "unexpected error: {error}"
+    );
+}
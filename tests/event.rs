@@ -0,0 +1,244 @@
+use libp2p::swarm::ConnectionId;
+use libp2p::{PeerId, StreamProtocol};
+use libp2p_messaging::error::Error;
+use libp2p_messaging::{Event, MessageId};
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/messaging-test/1.0.0");
+
+/// Names the variant of an event. Every field is destructured, so a change to the shape of any
+/// variant fails to compile here.
+fn variant_name(event: &Event<Vec<u8>>) -> &'static str {
+    match event {
+        Event::ReceivedMessage {
+            peer_id: _,
+            protocol: _,
+            remote_address: _,
+            message: _,
+            size: _,
+        } => "ReceivedMessage",
+        Event::InboundRequest {
+            peer_id: _,
+            protocol: _,
+            request_id: _,
+            message: _,
+            channel: _,
+        } => "InboundRequest",
+        Event::Response {
+            peer_id: _,
+            protocol: _,
+            request_id: _,
+            message: _,
+        } => "Response",
+        Event::MessageSent {
+            peer_id: _,
+            message_id: _,
+            size: _,
+        } => "MessageSent",
+        Event::MessageAcked {
+            peer_id: _,
+            message_id: _,
+            size: _,
+        } => "MessageAcked",
+        Event::OutboundQueued {
+            peer_id: _,
+            message_id: _,
+        } => "OutboundQueued",
+        Event::MessageCancelled {
+            peer_id: _,
+            message_id: _,
+        } => "MessageCancelled",
+        Event::InboundFailure {
+            peer_id: _,
+            message_id: _,
+            remote_address: _,
+            error: _,
+        } => "InboundFailure",
+        Event::BulkFailure {
+            peer_id: _,
+            message_ids: _,
+            remote_address: _,
+            error: _,
+        } => "BulkFailure",
+        Event::OutboundFailure {
+            peer_id: _,
+            message_id: _,
+            error: _,
+        } => "OutboundFailure",
+        Event::PeerConnected {
+            peer_id: _,
+            connection_id: _,
+            remote_address: _,
+        } => "PeerConnected",
+        Event::ProtocolNegotiated {
+            peer_id: _,
+            connection_id: _,
+            protocol: _,
+        } => "ProtocolNegotiated",
+        Event::InboundStreamClosed { peer_id: _ } => "InboundStreamClosed",
+        Event::PeerDisconnected { peer_id: _ } => "PeerDisconnected",
+        Event::SequenceGap {
+            peer_id: _,
+            connection_id: _,
+            expected: _,
+            got: _,
+        } => "SequenceGap",
+        Event::InboundRejected { peer_id: _ } => "InboundRejected",
+        Event::DuplicateDropped {
+            peer_id: _,
+            dedup_key: _,
+        } => "DuplicateDropped",
+        Event::PeerFlushed { peer_id: _ } => "PeerFlushed",
+        Event::Error(_) => "Error",
+        _ => "unknown",
+    }
+}
+
+#[test]
+fn every_event_variant_can_be_constructed_and_matched() {
+    let peer_id = PeerId::random();
+    let message_id = MessageId::new(1);
+    let connection_id = ConnectionId::new_unchecked(1);
+    let remote_address = Some("/memory/1".parse().unwrap());
+
+    let events = vec![
+        (
+            Event::ReceivedMessage {
+                peer_id,
+                protocol: PROTOCOL,
+                remote_address: remote_address.clone(),
+                message: b"hello".to_vec(),
+                size: 9,
+            },
+            "ReceivedMessage",
+            None,
+        ),
+        (
+            Event::Response {
+                peer_id,
+                protocol: PROTOCOL,
+                request_id: message_id,
+                message: b"hello".to_vec(),
+            },
+            "Response",
+            Some(message_id),
+        ),
+        (
+            Event::MessageSent {
+                peer_id,
+                message_id,
+                size: 9,
+            },
+            "MessageSent",
+            Some(message_id),
+        ),
+        (
+            Event::MessageAcked {
+                peer_id,
+                message_id,
+                size: 9,
+            },
+            "MessageAcked",
+            Some(message_id),
+        ),
+        (
+            Event::OutboundQueued {
+                peer_id,
+                message_id,
+            },
+            "OutboundQueued",
+            Some(message_id),
+        ),
+        (
+            Event::MessageCancelled {
+                peer_id,
+                message_id,
+            },
+            "MessageCancelled",
+            Some(message_id),
+        ),
+        (
+            Event::InboundFailure {
+                peer_id,
+                message_id,
+                remote_address: remote_address.clone(),
+                error: Error::RateLimited { peer_id },
+            },
+            "InboundFailure",
+            None,
+        ),
+        (
+            Event::BulkFailure {
+                peer_id,
+                message_ids: vec![message_id],
+                remote_address: remote_address.clone(),
+                error: Error::ConnectionClosed,
+            },
+            "BulkFailure",
+            None,
+        ),
+        (
+            Event::OutboundFailure {
+                peer_id,
+                message_id,
+                error: Error::ProtocolNotSupported,
+            },
+            "OutboundFailure",
+            Some(message_id),
+        ),
+        (
+            Event::PeerConnected {
+                peer_id,
+                connection_id,
+                remote_address,
+            },
+            "PeerConnected",
+            None,
+        ),
+        (
+            Event::ProtocolNegotiated {
+                peer_id,
+                connection_id,
+                protocol: PROTOCOL,
+            },
+            "ProtocolNegotiated",
+            None,
+        ),
+        (
+            Event::InboundStreamClosed { peer_id },
+            "InboundStreamClosed",
+            None,
+        ),
+        (
+            Event::PeerDisconnected { peer_id },
+            "PeerDisconnected",
+            None,
+        ),
+        (
+            Event::SequenceGap {
+                peer_id,
+                connection_id,
+                expected: 1,
+                got: 2,
+            },
+            "SequenceGap",
+            None,
+        ),
+        (Event::InboundRejected { peer_id }, "InboundRejected", None),
+        (
+            Event::DuplicateDropped {
+                peer_id,
+                dedup_key: 7,
+            },
+            "DuplicateDropped",
+            None,
+        ),
+        (Event::PeerFlushed { peer_id }, "PeerFlushed", None),
+        (Event::Error(Error::Evicted { peer_id }), "Error", None),
+    ];
+
+    for (event, name, expected_message_id) in events {
+        assert_eq!(variant_name(&event), name);
+        assert_eq!(event.peer_id(), Some(peer_id), "{name}");
+        assert_eq!(event.message_id(), expected_message_id, "{name}");
+    }
+}
@@ -0,0 +1,2520 @@
+use async_trait::async_trait;
+use libp2p::core::transport::MemoryTransport;
+use libp2p::core::upgrade::Version;
+use libp2p::futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use libp2p::swarm::{ConnectionId, SwarmEvent};
+use libp2p::{plaintext, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder, Transport};
+use libp2p_messaging::error::{ConfigError, Error, TimeoutPhase};
+use libp2p_messaging::{
+    Behaviour, CapacityPolicy, Codec, Config, ConnectionPriority, Event, SaturationPolicy,
+};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/messaging-test/1.0.0");
+
+/// Writes each message as a u32 length prefix followed by the message bytes.
+#[derive(Debug, Clone, Default)]
+struct TestCodec;
+
+#[async_trait]
+impl Codec for TestCodec {
+    type Message = Vec<u8>;
+
+    async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut len_buf = [0u8; 4];
+        reader.read_exact(&mut len_buf).await?;
+        let mut message = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+        reader.read_exact(&mut message).await?;
+        Ok(message)
+    }
+
+    async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        writer
+            .write_all(&(message.len() as u32).to_be_bytes())
+            .await?;
+        writer.write_all(&message).await
+    }
+}
+
+#[cfg(feature = "bytes")]
+/// Writes the message as is, so that a test controls the frame.
+#[derive(Debug, Clone, Default)]
+struct RawCodec;
+
+#[cfg(feature = "bytes")]
+#[async_trait]
+impl Codec for RawCodec {
+    type Message = Vec<u8>;
+
+    async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut message = Vec::new();
+        reader.read_to_end(&mut message).await?;
+        Ok(message)
+    }
+
+    async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        writer.write_all(&message).await
+    }
+}
+
+/// Decodes like [`TestCodec`], but takes [`SlowCodec::DELAY`] to decode the message `slow`.
+#[derive(Debug, Clone, Default)]
+struct SlowCodec;
+
+impl SlowCodec {
+    const DELAY: Duration = Duration::from_secs(2);
+}
+
+#[async_trait]
+impl Codec for SlowCodec {
+    type Message = Vec<u8>;
+
+    async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let message = TestCodec.decode_from(reader).await?;
+        if message == b"slow" {
+            tokio::time::sleep(Self::DELAY).await;
+        }
+        Ok(message)
+    }
+
+    async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        TestCodec.encode_to(writer, message).await
+    }
+}
+
+/// Encodes like [`TestCodec`], but takes [`SlowCodec::DELAY`] to encode the message `slow`.
+#[derive(Debug, Clone, Default)]
+struct SlowEncodeCodec;
+
+#[async_trait]
+impl Codec for SlowEncodeCodec {
+    type Message = Vec<u8>;
+
+    async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        TestCodec.decode_from(reader).await
+    }
+
+    async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        if message == b"slow" {
+            tokio::time::sleep(SlowCodec::DELAY).await;
+        }
+        TestCodec.encode_to(writer, message).await
+    }
+}
+
+fn new_swarm() -> Swarm<Behaviour<TestCodec>> {
+    new_swarm_with_config(Config::default())
+}
+
+fn new_swarm_with_config<C>(config: Config) -> Swarm<Behaviour<C>>
+where
+    C: Codec + Default + Send + Clone + 'static,
+{
+    new_swarm_with_protocols(vec![PROTOCOL], config)
+}
+
+fn new_swarm_with_protocols<C>(
+    protocols: Vec<StreamProtocol>,
+    config: Config,
+) -> Swarm<Behaviour<C>>
+where
+    C: Codec + Default + Send + Clone + 'static,
+{
+    new_swarm_with_behaviour(Behaviour::new(protocols, config).expect("valid config"))
+}
+
+fn new_swarm_with_behaviour<C>(behaviour: Behaviour<C>) -> Swarm<Behaviour<C>>
+where
+    C: Codec + Send + Clone + 'static,
+{
+    new_swarm_with_idle_timeout(behaviour, Duration::from_secs(10))
+}
+
+fn new_swarm_with_idle_timeout<C>(
+    behaviour: Behaviour<C>,
+    idle_timeout: Duration,
+) -> Swarm<Behaviour<C>>
+where
+    C: Codec + Send + Clone + 'static,
+{
+    SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_other_transport(|key| {
+            MemoryTransport::default()
+                .upgrade(Version::V1)
+                .authenticate(plaintext::Config::new(key))
+                .multiplex(yamux::Config::default())
+        })
+        .expect("memory transport is infallible")
+        .with_behaviour(|_| behaviour)
+        .expect("behaviour is infallible")
+        .with_swarm_config(|config| config.with_idle_connection_timeout(idle_timeout))
+        .build()
+}
+
+/// Listens on a new memory address, returning it once the swarm reports it.
+async fn listen<C>(swarm: &mut Swarm<Behaviour<C>>) -> Multiaddr
+where
+    C: Codec + Send + Clone + 'static,
+{
+    swarm
+        .listen_on("/memory/0".parse().unwrap())
+        .expect("listen on memory address");
+    loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+            return address;
+        }
+    }
+}
+
+/// Opens a new connection from `dialer` to `listener`, listening first if the listener has no
+/// address yet, and returns the id of the connection once the dialer has established it.
+async fn connect<A, B>(
+    dialer: &mut Swarm<Behaviour<A>>,
+    listener: &mut Swarm<Behaviour<B>>,
+) -> ConnectionId
+where
+    A: Codec + Send + Clone + 'static,
+    B: Codec + Send + Clone + 'static,
+{
+    let address = listener.listeners().next().cloned();
+    let address = match address {
+        Some(address) => address,
+        None => listen(listener).await,
+    };
+    let opts = DialOpts::peer_id(*listener.local_peer_id())
+        .addresses(vec![address])
+        .condition(PeerCondition::Always)
+        .build();
+    let dialed_id = opts.connection_id();
+    dialer.dial(opts).expect("dial listener");
+    let established = async {
+        loop {
+            tokio::select! {
+                event = dialer.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { connection_id, .. }
+                        if connection_id == dialed_id =>
+                    {
+                        return connection_id;
+                    }
+                    SwarmEvent::OutgoingConnectionError { error, .. } => {
+                        panic!("failed to connect: {error}");
+                    }
+                    _ => {}
+                },
+                _ = listener.select_next_some() => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), established)
+        .await
+        .expect("connecting timed out")
+}
+
+#[tokio::test]
+async fn message_is_received_over_swarm() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm();
+    let sender_id = *sender.local_peer_id();
+    let receiver_id = *receiver.local_peer_id();
+
+    let address = listen(&mut receiver).await;
+
+    let message_id =
+        sender
+            .behaviour_mut()
+            .send_message_to_addr(receiver_id, address, b"hello".to_vec());
+
+    let exchange = async {
+        let mut is_sent = false;
+        let mut received = None;
+        while !is_sent || received.is_none() {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::MessageSent { peer_id, message_id: id, .. }) => {
+                        assert_eq!(peer_id, receiver_id);
+                        assert_eq!(id, message_id);
+                        is_sent = true;
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { error, .. }) => {
+                        panic!("failed to send message: {error}");
+                    }
+                    _ => {}
+                },
+                event = receiver.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::ReceivedMessage { peer_id, message, .. }) = event {
+                        received = Some((peer_id, message));
+                    }
+                }
+            }
+        }
+        received.expect("checked by loop condition")
+    };
+
+    let (peer_id, message) = tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+    assert_eq!(peer_id, sender_id);
+    assert_eq!(message, b"hello");
+}
+
+#[tokio::test]
+async fn message_sent_events_name_the_peer_sent_to() {
+    let mut sender = new_swarm();
+    let mut alice = new_swarm();
+    let mut bob = new_swarm();
+    let alice_id = *alice.local_peer_id();
+    let bob_id = *bob.local_peer_id();
+
+    connect(&mut sender, &mut alice).await;
+    connect(&mut sender, &mut bob).await;
+
+    let to_alice = sender
+        .behaviour_mut()
+        .send_message(alice_id, b"alice".to_vec());
+    let to_bob = sender.behaviour_mut().send_message(bob_id, b"bob".to_vec());
+
+    let exchange = async {
+        let mut sent = Vec::new();
+        while sent.len() < 2 {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::MessageSent { peer_id, message_id, .. }) => {
+                        sent.push((message_id, peer_id));
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { error, .. }) => {
+                        panic!("failed to send message: {error}");
+                    }
+                    _ => {}
+                },
+                _ = alice.select_next_some() => {}
+                _ = bob.select_next_some() => {}
+            }
+        }
+        sent
+    };
+
+    let mut sent = tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+    sent.sort_by_key(|(message_id, _)| *message_id == to_bob);
+    assert_eq!(sent, [(to_alice, alice_id), (to_bob, bob_id)]);
+}
+
+#[tokio::test]
+async fn behaviour_with_protocol_exchanges_messages() {
+    let mut sender = new_swarm_with_behaviour(Behaviour::<TestCodec>::with_protocol(PROTOCOL));
+    let mut receiver = new_swarm_with_behaviour(Behaviour::<TestCodec>::with_protocol(PROTOCOL));
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"hello".to_vec());
+    let (message, _) = receive_one(&mut sender, &mut receiver).await;
+    assert_eq!(message, b"hello");
+}
+
+#[test]
+fn behaviour_rejects_an_invalid_config() {
+    let config = Config {
+        max_concurrent_streams: 0,
+        ..Config::default()
+    };
+    let result = Behaviour::<TestCodec>::new([PROTOCOL], config);
+    assert_eq!(result.err(), Some(ConfigError::ZeroMaxConcurrentStreams));
+    let result = Behaviour::<TestCodec>::new([], Config::default());
+    assert_eq!(result.err(), Some(ConfigError::NoProtocols));
+}
+
+#[tokio::test]
+async fn redundant_message_is_sent_once_when_a_connection_fails() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+
+    let connection_id = connect(&mut sender, &mut receiver).await;
+    connect(&mut sender, &mut receiver).await;
+
+    let message_id = sender
+        .behaviour_mut()
+        .send_message_redundant(receiver_id, b"hello".to_vec());
+    sender.close_connection(connection_id);
+
+    let exchange = async {
+        let mut num_sent = 0;
+        // Keep polling for a while after the message is sent to catch duplicate events
+        let settle = tokio::time::sleep(Duration::from_secs(10));
+        tokio::pin!(settle);
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::MessageSent { message_id: id, .. }) => {
+                        assert_eq!(id, message_id);
+                        num_sent += 1;
+                        settle
+                            .as_mut()
+                            .reset(tokio::time::Instant::now() + Duration::from_millis(500));
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { error, .. }) => {
+                        panic!("redundant message failed: {error}");
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+                _ = &mut settle => break,
+            }
+        }
+        num_sent
+    };
+
+    assert_eq!(exchange.await, 1);
+}
+
+#[tokio::test]
+async fn queued_message_expires_before_connection_is_established() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+
+    let address = listen(&mut receiver).await;
+
+    let sent_id =
+        sender
+            .behaviour_mut()
+            .send_message_to_addr(receiver_id, address, b"hello".to_vec());
+    let expired_id = sender.behaviour_mut().send_message_with_ttl(
+        receiver_id,
+        Duration::from_millis(1),
+        b"stale".to_vec(),
+    );
+    // Both messages stay queued until the sender is polled and dials the receiver
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let exchange = async {
+        let mut is_sent = false;
+        let mut is_expired = false;
+        while !is_sent || !is_expired {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::MessageSent { message_id, .. }) => {
+                        assert_eq!(message_id, sent_id);
+                        is_sent = true;
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { message_id, error, .. }) => {
+                        assert_eq!(message_id, expired_id);
+                        assert!(matches!(error, Error::Expired), "unexpected error: {error}");
+                        is_expired = true;
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+}
+
+#[tokio::test]
+async fn messages_beyond_the_pending_outbound_cap_fail_with_queue_full() {
+    let config = Config::builder()
+        .max_pending_outbound_per_peer(2)
+        .build()
+        .expect("valid config");
+    let mut sender = new_swarm_with_config::<TestCodec>(config);
+    // The peer has no known address, so its messages stay queued until dialing fails
+    let peer_id = PeerId::random();
+
+    let queued_ids = [
+        sender
+            .behaviour_mut()
+            .send_message(peer_id, b"first".to_vec()),
+        sender
+            .behaviour_mut()
+            .send_message(peer_id, b"second".to_vec()),
+    ];
+    let overflow_id = sender
+        .behaviour_mut()
+        .send_message(peer_id, b"third".to_vec());
+
+    let events = async {
+        let mut num_queued = 0;
+        let mut num_failed = 0;
+        while num_queued < queued_ids.len() || num_failed < queued_ids.len() + 1 {
+            match sender.select_next_some().await {
+                SwarmEvent::Behaviour(Event::OutboundQueued { message_id, .. }) => {
+                    assert!(queued_ids.contains(&message_id));
+                    num_queued += 1;
+                }
+                SwarmEvent::Behaviour(Event::OutboundFailure {
+                    message_id, error, ..
+                }) => {
+                    // Only the message past the cap is rejected as queue full
+                    assert_eq!(
+                        matches!(error, Error::QueueFull),
+                        message_id == overflow_id,
+                        "unexpected error for {message_id}: {error}"
+                    );
+                    num_failed += 1;
+                }
+                _ => {}
+            }
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), events)
+        .await
+        .expect("queue events timed out");
+}
+
+#[cfg(feature = "bytes")]
+#[tokio::test]
+async fn oversized_message_fails_without_dialing() {
+    use libp2p_messaging::bytes::BytesCodec;
+
+    let config = Config::builder()
+        .max_message_size(16)
+        .build()
+        .expect("valid config");
+    let mut sender = new_swarm_with_config::<BytesCodec>(config);
+    let mut receiver = new_swarm_with_config::<BytesCodec>(Config::default());
+    let receiver_id = *receiver.local_peer_id();
+    let address = listen(&mut receiver).await;
+
+    let message_id = sender.behaviour_mut().send_message_to_addr(
+        receiver_id,
+        address,
+        bytes::Bytes::from(vec![0; 100]),
+    );
+
+    // The failure is the first event, before any dial is attempted
+    let event = tokio::time::timeout(Duration::from_secs(10), sender.select_next_some())
+        .await
+        .expect("no event");
+    let SwarmEvent::Behaviour(Event::OutboundFailure {
+        message_id: id,
+        error,
+        ..
+    }) = event
+    else {
+        panic!("unexpected event {event:?}");
+    };
+    assert_eq!(id, message_id);
+    assert!(
+        matches!(
+            error,
+            Error::MessageTooLarge {
+                len: 104,
+                limit: 16
+            }
+        ),
+        "unexpected error: {error}"
+    );
+
+    let dialed = tokio::time::timeout(Duration::from_millis(200), async {
+        loop {
+            if let SwarmEvent::Dialing { .. } = sender.select_next_some().await {
+                break;
+            }
+        }
+    });
+    assert!(dialed.await.is_err(), "the receiver was dialed");
+}
+
+#[tokio::test]
+async fn message_to_an_unconnected_peer_is_queued_until_connected() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+    let address = listen(&mut receiver).await;
+
+    let message_id =
+        sender
+            .behaviour_mut()
+            .send_message_to_addr(receiver_id, address, b"hello".to_vec());
+
+    let exchange = async {
+        let mut outcomes = Vec::new();
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::OutboundQueued { peer_id, message_id: id }) => {
+                        assert_eq!(peer_id, receiver_id);
+                        assert_eq!(id, message_id);
+                        outcomes.push("queued");
+                    }
+                    SwarmEvent::ConnectionEstablished { .. } => outcomes.push("connected"),
+                    SwarmEvent::Behaviour(Event::MessageSent { message_id: id, .. }) => {
+                        assert_eq!(id, message_id);
+                        outcomes.push("sent");
+                        return outcomes;
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { error, .. }) => {
+                        panic!("failed to send message: {error}");
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    let outcomes = tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+    assert_eq!(outcomes, ["queued", "connected", "sent"]);
+
+    // A message to a connected peer is sent without being queued
+    let message_id = sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"again".to_vec());
+    let exchange = async {
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::OutboundQueued { .. }) => {
+                        panic!("message to a connected peer was queued");
+                    }
+                    SwarmEvent::Behaviour(Event::MessageSent { message_id: id, .. }) => {
+                        assert_eq!(id, message_id);
+                        break;
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+}
+
+#[tokio::test]
+async fn awaitable_message_resolves_once_sent() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    let sent = sender
+        .behaviour_mut()
+        .send_message_awaitable(receiver_id, b"hello".to_vec());
+    tokio::pin!(sent);
+    let exchange = async {
+        let mut message_id = None;
+        let mut received = None;
+        while message_id.is_none() || received.is_none() {
+            tokio::select! {
+                result = &mut sent, if message_id.is_none() => {
+                    message_id = Some(result.expect("message sent"));
+                }
+                event = sender.select_next_some() => {
+                    // The outcome is delivered to the future instead
+                    if let SwarmEvent::Behaviour(Event::MessageSent { .. }) = event {
+                        panic!("awaited message was reported with an event");
+                    }
+                }
+                event = receiver.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::ReceivedMessage { message, .. }) = event {
+                        received = Some(message);
+                    }
+                }
+            }
+        }
+        (message_id, received)
+    };
+
+    let (message_id, received) = tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+    assert!(message_id.is_some());
+    assert_eq!(received.as_deref(), Some(&b"hello"[..]));
+}
+
+#[tokio::test]
+async fn awaitable_message_resolves_with_the_failure() {
+    let mut sender = new_swarm();
+    // The peer has no known address, so dialing it fails
+    let sent = sender
+        .behaviour_mut()
+        .send_message_awaitable(PeerId::random(), b"hello".to_vec());
+    tokio::pin!(sent);
+
+    let outcome = async {
+        loop {
+            tokio::select! {
+                result = &mut sent => break result,
+                _ = sender.select_next_some() => {}
+            }
+        }
+    };
+    let result = tokio::time::timeout(Duration::from_secs(10), outcome)
+        .await
+        .expect("message neither sent nor failed");
+    assert!(
+        matches!(result, Err(Error::DialFailure)),
+        "unexpected result: {result:?}"
+    );
+}
+
+#[tokio::test]
+async fn messages_queued_while_paused_are_sent_after_resume() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    sender.behaviour_mut().pause_outbound();
+    let message_id = sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"hello".to_vec());
+
+    let paused = async {
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => {
+                    if let SwarmEvent::Behaviour(
+                        event @ (Event::MessageSent { .. } | Event::OutboundFailure { .. }),
+                    ) = event
+                    {
+                        panic!("unexpected event while paused: {event:?}");
+                    }
+                }
+                event = receiver.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::ReceivedMessage { .. }) = event {
+                        panic!("message received while paused");
+                    }
+                }
+            }
+        }
+    };
+    // The message must not be sent while paused
+    let _ = tokio::time::timeout(Duration::from_millis(500), paused).await;
+
+    sender.behaviour_mut().resume_outbound();
+    let exchange = async {
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::MessageSent { message_id: id, .. }) => {
+                        assert_eq!(id, message_id);
+                        break;
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { error, .. }) => {
+                        panic!("failed to send message: {error}");
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+}
+
+#[tokio::test]
+async fn slow_decode_times_out_independently_of_concurrent_decode() {
+    let mut sender = new_swarm_with_config::<SlowCodec>(Config::default());
+    let mut receiver = new_swarm_with_config::<SlowCodec>(
+        Config::builder()
+            .send_recv_timeout(Duration::from_millis(500))
+            .build()
+            .expect("valid config"),
+    );
+    let receiver_id = *receiver.local_peer_id();
+
+    let address = listen(&mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message_to_addr(receiver_id, address, b"slow".to_vec());
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"fast".to_vec());
+
+    let exchange = async {
+        let mut is_received = false;
+        let mut is_timed_out = false;
+        while !is_received || !is_timed_out {
+            tokio::select! {
+                _ = sender.select_next_some() => {}
+                event = receiver.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::ReceivedMessage { message, .. }) => {
+                        assert_eq!(message, b"fast", "the slow message should time out");
+                        is_received = true;
+                    }
+                    SwarmEvent::Behaviour(Event::InboundFailure {
+                        error: Error::Timeout(TimeoutPhase::Read),
+                        ..
+                    }) => {
+                        is_timed_out = true;
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+
+    // Both complete well before the slow decode would have
+    tokio::time::timeout(SlowCodec::DELAY, exchange)
+        .await
+        .expect("message exchange timed out");
+}
+
+#[tokio::test]
+async fn inbound_streams_beyond_the_per_peer_limit_are_rate_limited() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm_with_config::<SlowCodec>(
+        Config::builder()
+            .max_inbound_streams_per_peer(1)
+            .build()
+            .expect("valid config"),
+    );
+    let sender_id = *sender.local_peer_id();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    // The slow message holds the peer's only inbound stream while the others arrive
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"slow".to_vec());
+    wait_until_sent(&mut sender, &mut receiver).await;
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"first".to_vec());
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"second".to_vec());
+
+    let exchange = async {
+        let mut failures = Vec::new();
+        loop {
+            tokio::select! {
+                _ = sender.select_next_some() => {}
+                event = receiver.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::ReceivedMessage { message, .. }) => {
+                        assert_eq!(message, b"slow", "excess streams should be rejected");
+                        return failures;
+                    }
+                    SwarmEvent::Behaviour(Event::InboundFailure {
+                        peer_id,
+                        message_id,
+                        remote_address,
+                        error: Error::RateLimited { .. },
+                    }) => {
+                        assert_eq!(peer_id, sender_id);
+                        assert!(remote_address.is_some());
+                        failures.push(message_id);
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    let failures = tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+    assert_eq!(failures.len(), 2);
+    assert_ne!(failures[0], failures[1]);
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn inbound_stream_dropped_at_capacity_is_counted() {
+    use libp2p_messaging::Metrics;
+    use prometheus_client::registry::Registry;
+
+    /// Returns the value of the metric in the registry's text encoding.
+    fn metric_value(registry: &Registry, metric: &str) -> u64 {
+        let mut encoded = String::new();
+        prometheus_client::encoding::text::encode(&mut encoded, registry).expect("encode metrics");
+        encoded
+            .lines()
+            .find_map(|line| line.strip_prefix(metric)?.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    let mut registry = Registry::default();
+    let metrics = Metrics::new(&mut registry);
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm_with_config::<SlowCodec>(
+        Config::builder()
+            .max_concurrent_streams(1)
+            .metrics(metrics)
+            .build()
+            .expect("valid config"),
+    );
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    // The slow message holds the receiver's only stream slot while the next stream arrives
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"slow".to_vec());
+    wait_until_sent(&mut sender, &mut receiver).await;
+    assert_eq!(
+        metric_value(
+            &registry,
+            r#"messaging_streams_dropped_total{direction="inbound"}"#
+        ),
+        0
+    );
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"dropped".to_vec());
+
+    let (message, _) = receive_one(&mut sender, &mut receiver).await;
+    assert_eq!(message, b"slow");
+    assert!(
+        metric_value(
+            &registry,
+            r#"messaging_streams_dropped_total{direction="inbound"}"#
+        ) > 0
+    );
+    assert_eq!(
+        metric_value(&registry, "messaging_messages_received_total"),
+        1
+    );
+}
+
+#[cfg(feature = "bytes")]
+#[tokio::test]
+async fn oversized_inbound_message_is_reported_with_its_size() {
+    use libp2p_messaging::bytes::BytesCodec;
+
+    let mut sender = new_swarm_with_config::<RawCodec>(Config::default());
+    let mut receiver = new_swarm_with_config::<BytesCodec>(Config::default());
+    let sender_id = *sender.local_peer_id();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    // A length prefix larger than the codec accepts, without the message it announces
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, u32::MAX.to_be_bytes().to_vec());
+
+    let exchange = async {
+        loop {
+            tokio::select! {
+                _ = sender.select_next_some() => {}
+                event = receiver.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::InboundFailure { peer_id, error, .. }) => {
+                        assert_eq!(peer_id, sender_id);
+                        return error;
+                    }
+                    SwarmEvent::Behaviour(Event::ReceivedMessage { .. }) => {
+                        panic!("oversized message was received");
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    let error = tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+    assert!(
+        matches!(
+            error,
+            Error::MessageTooLarge {
+                len: 0xffff_ffff,
+                limit: 0x40_0000,
+            }
+        ),
+        "unexpected error: {error}"
+    );
+}
+
+#[tokio::test]
+async fn outbound_stream_beyond_the_connection_stream_limit_fails() {
+    let mut peer = new_swarm_with_config::<SlowCodec>(
+        Config::builder()
+            .max_concurrent_streams(1)
+            .build()
+            .expect("valid config"),
+    );
+    let mut remote = new_swarm();
+    let remote_id = *remote.local_peer_id();
+    let peer_id = *peer.local_peer_id();
+
+    // The remote dials, so that it knows of the connection when it sends
+    connect(&mut remote, &mut peer).await;
+
+    // The slow inbound message takes the peer's only stream slot
+    remote
+        .behaviour_mut()
+        .send_message(peer_id, b"slow".to_vec());
+    wait_until_sent(&mut remote, &mut peer).await;
+    let message_id = peer
+        .behaviour_mut()
+        .send_message(remote_id, b"hello".to_vec());
+
+    let exchange = async {
+        loop {
+            tokio::select! {
+                event = peer.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::OutboundFailure { message_id: id, error, .. }) => {
+                        assert_eq!(id, message_id);
+                        return error;
+                    }
+                    SwarmEvent::Behaviour(Event::MessageSent { .. }) => {
+                        panic!("message was sent while the stream slot was taken");
+                    }
+                    _ => {}
+                },
+                _ = remote.select_next_some() => {}
+            }
+        }
+    };
+    let error = tokio::time::timeout(SlowCodec::DELAY, exchange)
+        .await
+        .expect("message neither sent nor failed");
+    assert!(
+        matches!(error, Error::AtCapacity),
+        "unexpected error: {error}"
+    );
+    assert!(!peer.behaviour().has_pending(&remote_id));
+}
+
+#[tokio::test]
+async fn inbound_stream_is_failed_when_its_connection_closes() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm_with_config::<SlowCodec>(Config::default());
+    let sender_id = *sender.local_peer_id();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"slow".to_vec());
+    wait_until_sent(&mut sender, &mut receiver).await;
+    // The receiver closes the connection while it is still decoding the message
+    receiver
+        .disconnect_peer_id(sender_id)
+        .expect("connected to sender");
+
+    let exchange = async {
+        loop {
+            tokio::select! {
+                _ = sender.select_next_some() => {}
+                event = receiver.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::InboundFailure {
+                        peer_id,
+                        remote_address,
+                        error,
+                        ..
+                    }) => {
+                        assert_eq!(peer_id, sender_id);
+                        assert!(remote_address.is_some());
+                        return error;
+                    }
+                    SwarmEvent::Behaviour(Event::Error(error)) => {
+                        panic!("inbound stream failed without an id: {error}");
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    let error = tokio::time::timeout(SlowCodec::DELAY, exchange)
+        .await
+        .expect("inbound stream did not fail");
+    assert!(
+        matches!(error, Error::ConnectionClosed),
+        "unexpected error: {error}"
+    );
+}
+
+#[tokio::test]
+async fn oldest_inbound_stream_is_evicted_for_a_new_stream() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm_with_config::<SlowCodec>(
+        Config::builder()
+            .max_concurrent_streams(1)
+            .inbound_capacity_policy(CapacityPolicy::EvictOldest)
+            .build()
+            .expect("valid config"),
+    );
+    let sender_id = *sender.local_peer_id();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"slow".to_vec());
+    wait_until_sent(&mut sender, &mut receiver).await;
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"fast".to_vec());
+
+    let exchange = async {
+        let mut is_evicted = false;
+        loop {
+            tokio::select! {
+                _ = sender.select_next_some() => {}
+                event = receiver.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::ReceivedMessage { message, .. }) => {
+                        assert_eq!(message, b"fast", "the slow stream should be evicted");
+                        return is_evicted;
+                    }
+                    SwarmEvent::Behaviour(Event::InboundFailure {
+                        peer_id,
+                        error: Error::Evicted { .. },
+                        ..
+                    }) => {
+                        assert_eq!(peer_id, sender_id);
+                        is_evicted = true;
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    // The new stream is read well before the slow decode would have completed
+    let is_evicted = tokio::time::timeout(SlowCodec::DELAY, exchange)
+        .await
+        .expect("message exchange timed out");
+    assert!(
+        is_evicted,
+        "eviction should be reported before the new message"
+    );
+}
+
+#[tokio::test]
+async fn closing_connection_mid_send_fails_message() {
+    let config = || {
+        Config::builder()
+            .require_ack(true)
+            .build()
+            .expect("valid config")
+    };
+    let mut sender = new_swarm_with_config::<SlowCodec>(config());
+    let mut receiver = new_swarm_with_config::<SlowCodec>(config());
+    let receiver_id = *receiver.local_peer_id();
+
+    let address = listen(&mut receiver).await;
+
+    // The sender waits for the acknowledgement while the receiver is slowly decoding
+    let message_id =
+        sender
+            .behaviour_mut()
+            .send_message_to_addr(receiver_id, address, b"slow".to_vec());
+
+    let exchange = async {
+        let mut connection_id = None;
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { connection_id: id, .. } => {
+                        connection_id = Some(id);
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { message_id: id, error, .. }) => {
+                        assert_eq!(id, message_id);
+                        assert!(
+                            matches!(error, Error::ConnectionClosed),
+                            "unexpected error: {error}"
+                        );
+                        break;
+                    }
+                    SwarmEvent::Behaviour(Event::MessageAcked { .. }) => {
+                        panic!("message was acknowledged before the connection closed");
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+                // Close the connection once the message is being read
+                _ = tokio::time::sleep(Duration::from_millis(200)), if connection_id.is_some() => {
+                    sender.close_connection(connection_id.take().expect("checked by guard"));
+                }
+            }
+        }
+    };
+
+    tokio::time::timeout(SlowCodec::DELAY, exchange)
+        .await
+        .expect("message did not fail");
+}
+
+/// Waits for the sender to finish sending its next message.
+async fn wait_until_sent<A, B>(sender: &mut Swarm<Behaviour<A>>, receiver: &mut Swarm<Behaviour<B>>)
+where
+    A: Codec + Send + Clone + 'static,
+    B: Codec + Send + Clone + 'static,
+{
+    let sent = async {
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::MessageSent { .. }) = event {
+                        break;
+                    }
+                }
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), sent)
+        .await
+        .expect("sending timed out");
+}
+
+/// Waits for the receiver to receive the next message, returning it along with the sequence gap
+/// reported before it, if any.
+async fn receive_one<A, B>(
+    sender: &mut Swarm<Behaviour<A>>,
+    receiver: &mut Swarm<Behaviour<B>>,
+) -> (Vec<u8>, Option<(u64, u64)>)
+where
+    A: Codec + Send + Clone + 'static,
+    B: Codec<Message = Vec<u8>> + Send + Clone + 'static,
+{
+    let sender_id = *sender.local_peer_id();
+    let mut gap = None;
+    let received = async {
+        loop {
+            tokio::select! {
+                _ = sender.select_next_some() => {}
+                event = receiver.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::SequenceGap { peer_id, expected, got, .. }) => {
+                        assert_eq!(peer_id, sender_id);
+                        gap = Some((expected, got));
+                    }
+                    SwarmEvent::Behaviour(Event::ReceivedMessage { message, .. }) => {
+                        return message;
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    let message = tokio::time::timeout(Duration::from_secs(10), received)
+        .await
+        .expect("receiving timed out");
+    (message, gap)
+}
+
+#[tokio::test]
+async fn acked_message_is_reported_and_unacked_message_times_out() {
+    let mut sender = new_swarm_with_config::<TestCodec>(
+        Config::builder()
+            .require_ack(true)
+            // Shorter than the receiver takes to decode the slow message
+            .send_recv_timeout(SlowCodec::DELAY / 4)
+            .build()
+            .expect("valid config"),
+    );
+    let mut receiver = new_swarm_with_config::<SlowCodec>(
+        Config::builder()
+            .require_ack(true)
+            .build()
+            .expect("valid config"),
+    );
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    let acked_id = sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"hello".to_vec());
+    let unacked_id = sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"slow".to_vec());
+
+    let exchange = async {
+        let mut is_acked = false;
+        let mut is_timed_out = false;
+        while !is_acked || !is_timed_out {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::MessageAcked { peer_id, message_id, .. }) => {
+                        assert_eq!(peer_id, receiver_id);
+                        assert_eq!(message_id, acked_id);
+                        is_acked = true;
+                    }
+                    SwarmEvent::Behaviour(Event::MessageSent { message_id, .. }) => {
+                        panic!("message {message_id} was reported sent rather than acked");
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { message_id, error, .. }) => {
+                        assert_eq!(message_id, unacked_id, "unexpected failure: {error}");
+                        assert!(
+                            matches!(error, Error::Timeout(TimeoutPhase::Write)),
+                            "unexpected error: {error}"
+                        );
+                        is_timed_out = true;
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+}
+
+#[tokio::test]
+async fn cancelled_message_is_not_reported_as_sequence_gap() {
+    let config = || {
+        Config::builder()
+            .sequence_gap_detection(true)
+            .build()
+            .expect("valid config")
+    };
+    let mut sender = new_swarm_with_config::<TestCodec>(config());
+    let mut receiver = new_swarm_with_config::<TestCodec>(config());
+    let receiver_id = *receiver.local_peer_id();
+
+    let address = listen(&mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message_to_addr(receiver_id, address, b"first".to_vec());
+    let (message, gap) = receive_one(&mut sender, &mut receiver).await;
+    assert_eq!(message, b"first");
+    assert_eq!(gap, None);
+
+    // The cancelled message is never written to a stream, so it is not numbered
+    sender.behaviour_mut().pause_outbound();
+    let skipped_id = sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"skipped".to_vec());
+    assert!(sender
+        .behaviour_mut()
+        .cancel_message(&receiver_id, skipped_id));
+    sender.behaviour_mut().resume_outbound();
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"third".to_vec());
+
+    let (message, gap) = receive_one(&mut sender, &mut receiver).await;
+    assert_eq!(message, b"third");
+    assert_eq!(gap, None);
+}
+
+#[tokio::test]
+async fn dropped_message_is_reported_as_sequence_gap() {
+    let sender_config = Config::builder()
+        .sequence_gap_detection(true)
+        .build()
+        .expect("valid config");
+    // A message that arrives while another is decoded is dropped by the receiver
+    let receiver_config = Config::builder()
+        .sequence_gap_detection(true)
+        .max_inbound_streams_per_peer(1)
+        .build()
+        .expect("valid config");
+    let mut sender = new_swarm_with_config::<TestCodec>(sender_config);
+    let mut receiver = new_swarm_with_config::<SlowCodec>(receiver_config);
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"slow".to_vec());
+    wait_until_sent(&mut sender, &mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"dropped".to_vec());
+    let dropped = async {
+        loop {
+            tokio::select! {
+                _ = sender.select_next_some() => {}
+                event = receiver.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::InboundFailure {
+                        error: Error::RateLimited { .. },
+                        ..
+                    }) = event
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+    tokio::time::timeout(SlowCodec::DELAY, dropped)
+        .await
+        .expect("message was not dropped");
+
+    let (message, gap) = receive_one(&mut sender, &mut receiver).await;
+    assert_eq!(message, b"slow");
+    assert_eq!(gap, None);
+
+    // The gap is reported once as many later messages as streams may be in flight concurrently
+    // have been received
+    let max_concurrent_streams = Config::default().max_concurrent_streams;
+    let mut gaps = Vec::new();
+    for i in 0..max_concurrent_streams {
+        sender
+            .behaviour_mut()
+            .send_message(receiver_id, vec![i as u8]);
+        let (message, gap) = receive_one(&mut sender, &mut receiver).await;
+        assert_eq!(message, vec![i as u8]);
+        gaps.extend(gap);
+    }
+    assert_eq!(gaps, vec![(1, 2)]);
+}
+
+#[tokio::test]
+async fn message_with_a_seen_dedup_key_is_dropped() {
+    let config = || {
+        Config::builder()
+            .dedup_window(16)
+            .build()
+            .expect("valid config")
+    };
+    // Rejects the first message only, which must not count as delivered
+    let is_rejected = Arc::new(AtomicBool::new(false));
+    let filter = {
+        let is_rejected = is_rejected.clone();
+        move |_: &PeerId, _: &Vec<u8>| is_rejected.swap(true, Ordering::Relaxed)
+    };
+    let mut sender = new_swarm_with_config::<TestCodec>(config());
+    let mut receiver = new_swarm_with_behaviour(
+        Behaviour::<TestCodec>::new(vec![PROTOCOL], config())
+            .expect("valid config")
+            .with_inbound_filter(filter),
+    );
+    let sender_id = *sender.local_peer_id();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    let mut outcomes = Vec::new();
+    for _ in 0..3 {
+        sender
+            .behaviour_mut()
+            .send_message_with_dedup_key(receiver_id, 7, b"hello".to_vec());
+        let outcome = async {
+            loop {
+                tokio::select! {
+                    _ = sender.select_next_some() => {}
+                    event = receiver.select_next_some() => match event {
+                        SwarmEvent::Behaviour(Event::InboundRejected { peer_id }) => {
+                            assert_eq!(peer_id, sender_id);
+                            break "rejected";
+                        }
+                        SwarmEvent::Behaviour(Event::ReceivedMessage { peer_id, message, .. }) => {
+                            assert_eq!(peer_id, sender_id);
+                            assert_eq!(message, b"hello");
+                            break "received";
+                        }
+                        SwarmEvent::Behaviour(Event::DuplicateDropped { peer_id, dedup_key }) => {
+                            assert_eq!(peer_id, sender_id);
+                            assert_eq!(dedup_key, 7);
+                            break "dropped";
+                        }
+                        _ => {}
+                    },
+                }
+            }
+        };
+        let outcome = tokio::time::timeout(Duration::from_secs(10), outcome)
+            .await
+            .expect("receiving timed out");
+        outcomes.push(outcome);
+    }
+    assert!(is_rejected.load(Ordering::Relaxed));
+    assert_eq!(outcomes, ["rejected", "received", "dropped"]);
+}
+
+#[cfg(feature = "message-store")]
+#[tokio::test]
+async fn overflow_messages_are_stored_and_sent_once_when_connected() {
+    use libp2p_messaging::{MessageId, MessageStore, OutboundMessage};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, Default)]
+    struct MemoryStore(Arc<Mutex<Vec<OutboundMessage<Vec<u8>>>>>);
+
+    impl MessageStore<Vec<u8>> for MemoryStore {
+        fn persist(&mut self, message: OutboundMessage<Vec<u8>>) -> io::Result<()> {
+            self.0.lock().unwrap().push(message);
+            Ok(())
+        }
+
+        fn load(&mut self, peer_id: &PeerId) -> io::Result<Vec<OutboundMessage<Vec<u8>>>> {
+            let messages = self.0.lock().unwrap();
+            Ok(messages
+                .iter()
+                .filter(|message| message.peer_id == *peer_id)
+                .cloned()
+                .collect())
+        }
+
+        fn remove(&mut self, peer_id: &PeerId, message_id: MessageId) -> io::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .retain(|message| message.peer_id != *peer_id || message.message_id != message_id);
+            Ok(())
+        }
+    }
+
+    const NUM_MESSAGES: usize = 4;
+    let store = MemoryStore::default();
+    let mut sender = new_swarm_with_behaviour(
+        Behaviour::<TestCodec>::new(
+            vec![PROTOCOL],
+            Config::builder()
+                .max_pending_outbound_per_peer(1)
+                .build()
+                .expect("valid config"),
+        )
+        .expect("valid config")
+        .with_message_store(store.clone()),
+    );
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+    let address = listen(&mut receiver).await;
+
+    // Only the first message fits in the queue while the receiver is dialed
+    for i in 0..NUM_MESSAGES {
+        sender
+            .behaviour_mut()
+            .send_message_to_addr(receiver_id, address.clone(), vec![i as u8]);
+    }
+    assert_eq!(store.0.lock().unwrap().len(), NUM_MESSAGES - 1);
+
+    let exchange = async {
+        let mut received = Vec::new();
+        let mut num_sent = 0;
+        let mut is_redialed = false;
+        // Keep polling for a while after the last message to catch duplicates
+        let settle = tokio::time::sleep(Duration::from_secs(10));
+        tokio::pin!(settle);
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    // A second connection established alongside the first must not send the
+                    // stored messages again
+                    SwarmEvent::Dialing { .. } if !is_redialed => {
+                        is_redialed = true;
+                        let opts = DialOpts::peer_id(receiver_id)
+                            .addresses(vec![address.clone()])
+                            .condition(PeerCondition::Always)
+                            .build();
+                        sender.dial(opts).expect("dial receiver");
+                    }
+                    SwarmEvent::Behaviour(Event::MessageSent { .. }) => num_sent += 1,
+                    SwarmEvent::Behaviour(Event::OutboundFailure { error, .. }) => {
+                        panic!("failed to send message: {error}");
+                    }
+                    _ => {}
+                },
+                event = receiver.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::ReceivedMessage { message, .. }) = event {
+                        received.push(message);
+                        if received.len() == NUM_MESSAGES {
+                            settle
+                                .as_mut()
+                                .reset(tokio::time::Instant::now() + Duration::from_millis(500));
+                        }
+                    }
+                }
+                _ = &mut settle => break,
+            }
+        }
+        (received, num_sent)
+    };
+
+    let (mut received, num_sent) = exchange.await;
+    received.sort();
+    let expected = (0..NUM_MESSAGES).map(|i| vec![i as u8]).collect::<Vec<_>>();
+    assert_eq!(received, expected);
+    assert_eq!(num_sent, NUM_MESSAGES);
+    assert!(
+        store.0.lock().unwrap().is_empty(),
+        "sent messages should be removed"
+    );
+}
+
+#[tokio::test]
+async fn negotiated_protocol_version_is_reported() {
+    const V1: StreamProtocol = StreamProtocol::new("/messaging-test/1.0.0");
+    const V2: StreamProtocol = StreamProtocol::new("/messaging-test/2.0.0");
+
+    let mut sender = new_swarm_with_protocols::<TestCodec>(vec![V2, V1], Config::default());
+    let mut receiver = new_swarm_with_protocols::<TestCodec>(vec![V1], Config::default());
+    let receiver_id = *receiver.local_peer_id();
+
+    let address = listen(&mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message_to_addr(receiver_id, address, b"hello".to_vec());
+
+    let negotiate = async {
+        let mut connection_id = None;
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { connection_id: id, .. } => {
+                        connection_id = Some(id);
+                    }
+                    SwarmEvent::Behaviour(Event::ProtocolNegotiated {
+                        peer_id,
+                        connection_id: id,
+                        protocol,
+                    }) => {
+                        assert_eq!(peer_id, receiver_id);
+                        assert_eq!(Some(id), connection_id);
+                        break protocol;
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+
+    let protocol = tokio::time::timeout(Duration::from_secs(10), negotiate)
+        .await
+        .expect("negotiation timed out");
+    // The sender prefers the newer version, but the receiver only supports the older one
+    assert_eq!(protocol, V1);
+}
+
+#[tokio::test]
+async fn message_falls_back_to_a_protocol_the_receiver_supports() {
+    const V1: StreamProtocol = StreamProtocol::new("/messaging-test/1.0.0");
+    const V2: StreamProtocol = StreamProtocol::new("/messaging-test/2.0.0");
+
+    let mut sender = new_swarm_with_protocols::<TestCodec>(vec![V2, V1], Config::default());
+    let mut receiver = new_swarm_with_protocols::<TestCodec>(vec![V1], Config::default());
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"hello".to_vec());
+    let (message, _) = receive_one(&mut sender, &mut receiver).await;
+    assert_eq!(message, b"hello");
+}
+
+#[tokio::test]
+async fn message_fails_once_every_protocol_is_rejected() {
+    const V1: StreamProtocol = StreamProtocol::new("/messaging-test/1.0.0");
+    const V2: StreamProtocol = StreamProtocol::new("/messaging-test/2.0.0");
+    const V3: StreamProtocol = StreamProtocol::new("/messaging-test/3.0.0");
+
+    let mut sender = new_swarm_with_protocols::<TestCodec>(vec![V3, V2], Config::default());
+    let mut receiver = new_swarm_with_protocols::<TestCodec>(vec![V1], Config::default());
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    let message_id = sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"hello".to_vec());
+    let failed = async {
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::OutboundFailure { message_id: id, error, .. }) => {
+                        assert_eq!(id, message_id);
+                        break error;
+                    }
+                    SwarmEvent::Behaviour(Event::MessageSent { .. }) => {
+                        panic!("no protocol should have been negotiated");
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    let error = tokio::time::timeout(Duration::from_secs(10), failed)
+        .await
+        .expect("sending timed out");
+    assert!(matches!(error, Error::ProtocolNotSupported), "{error}");
+}
+
+#[tokio::test]
+async fn batched_messages_share_a_single_substream() {
+    const NUM_MESSAGES: usize = 50;
+    let config = || {
+        Config::builder()
+            .batch_window(Some(Duration::from_millis(50)))
+            // Reports the end of each inbound substream
+            .reuse_inbound_stream(true)
+            .build()
+            .expect("valid config")
+    };
+    let mut sender = new_swarm_with_config::<TestCodec>(config());
+    let mut receiver = new_swarm_with_config::<TestCodec>(config());
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    for i in 0..NUM_MESSAGES {
+        sender
+            .behaviour_mut()
+            .send_message(receiver_id, vec![i as u8]);
+    }
+
+    let exchange = async {
+        let mut received = Vec::new();
+        let mut num_sent = 0;
+        let mut num_streams = 0;
+        // Keep polling for a while after the stream closes to catch further streams
+        let settle = tokio::time::sleep(Duration::from_secs(10));
+        tokio::pin!(settle);
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::MessageSent { .. }) => num_sent += 1,
+                    SwarmEvent::Behaviour(Event::OutboundFailure { error, .. }) => {
+                        panic!("failed to send message: {error}");
+                    }
+                    _ => {}
+                },
+                event = receiver.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::ReceivedMessage { message, .. }) => {
+                        received.push(message);
+                    }
+                    SwarmEvent::Behaviour(Event::InboundStreamClosed { .. }) => {
+                        num_streams += 1;
+                        settle
+                            .as_mut()
+                            .reset(tokio::time::Instant::now() + Duration::from_millis(500));
+                    }
+                    _ => {}
+                },
+                _ = &mut settle => break,
+            }
+        }
+        (received, num_sent, num_streams)
+    };
+
+    let (received, num_sent, num_streams) = exchange.await;
+    assert_eq!(num_streams, 1);
+    assert_eq!(num_sent, NUM_MESSAGES);
+    let expected = (0..NUM_MESSAGES).map(|i| vec![i as u8]).collect::<Vec<_>>();
+    assert_eq!(received, expected);
+}
+
+#[tokio::test]
+async fn batched_messages_are_read_ahead_in_order() {
+    const NUM_MESSAGES: usize = 40;
+    let config = || {
+        Config::builder()
+            .batch_window(Some(Duration::from_millis(50)))
+            .sequence_gap_detection(true)
+            .require_ack(true)
+            // Smaller than the later messages, which are read past the buffer
+            .inbound_read_ahead(64)
+            .build()
+            .expect("valid config")
+    };
+    let mut sender = new_swarm_with_config::<TestCodec>(config());
+    let mut receiver = new_swarm_with_config::<TestCodec>(config());
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    let messages = (0..NUM_MESSAGES)
+        .map(|i| vec![i as u8; i * 7])
+        .collect::<Vec<_>>();
+    for message in &messages {
+        sender
+            .behaviour_mut()
+            .send_message(receiver_id, message.clone());
+    }
+    for expected in messages {
+        let (message, gap) = receive_one(&mut sender, &mut receiver).await;
+        assert_eq!(message, expected);
+        assert_eq!(gap, None);
+    }
+}
+
+#[tokio::test]
+async fn failed_write_to_a_batched_stream_fails_every_message_with_a_write_error() {
+    /// Encodes like [`TestCodec`], but closes the stream after writing the message `close`.
+    #[derive(Debug, Clone, Default)]
+    struct ClosingCodec;
+
+    #[async_trait]
+    impl Codec for ClosingCodec {
+        type Message = Vec<u8>;
+
+        async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+        where
+            R: AsyncRead + Unpin + Send,
+        {
+            TestCodec.decode_from(reader).await
+        }
+
+        async fn encode_to<W>(&mut self, writer: &mut W, message: Self::Message) -> io::Result<()>
+        where
+            W: AsyncWrite + Unpin + Send,
+        {
+            let close = message == b"close";
+            TestCodec.encode_to(writer, message).await?;
+            if close {
+                writer.close().await?;
+            }
+            Ok(())
+        }
+    }
+
+    let config = || {
+        Config::builder()
+            .batch_window(Some(Duration::from_millis(50)))
+            .build()
+            .expect("valid config")
+    };
+    let mut sender = new_swarm_with_config::<ClosingCodec>(config());
+    let mut receiver = new_swarm_with_config::<TestCodec>(config());
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    // The second message is buffered, and fails to be written once the buffer is flushed
+    let message_ids = [
+        sender
+            .behaviour_mut()
+            .send_message(receiver_id, b"close".to_vec()),
+        sender
+            .behaviour_mut()
+            .send_message(receiver_id, b"after".to_vec()),
+    ];
+
+    let exchange = async {
+        let mut failures = Vec::new();
+        while failures.len() < message_ids.len() {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::OutboundFailure { message_id, error, .. }) => {
+                        failures.push((message_id, error));
+                    }
+                    SwarmEvent::Behaviour(Event::MessageSent { message_id, .. }) => {
+                        panic!("message {message_id} was sent");
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+        failures
+    };
+    let failures = tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("messages neither sent nor failed");
+    for (message_id, error) in failures {
+        assert!(message_ids.contains(&message_id));
+        assert!(
+            matches!(error, Error::WriteError(_)),
+            "unexpected error for {message_id}: {error}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn batched_messages_raise_the_reuse_factor() {
+    const NUM_MESSAGES: usize = 10;
+    let config = Config::builder()
+        .batch_window(Some(Duration::from_millis(50)))
+        .build()
+        .expect("valid config");
+    let mut sender = new_swarm_with_config::<TestCodec>(config);
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    for i in 0..NUM_MESSAGES {
+        sender
+            .behaviour_mut()
+            .send_message(receiver_id, vec![i as u8]);
+    }
+    for _ in 0..NUM_MESSAGES {
+        wait_until_sent(&mut sender, &mut receiver).await;
+    }
+
+    let stats = sender.behaviour().peer_stats(&receiver_id);
+    let [connection] = stats.connections.as_slice() else {
+        panic!("expected a single connection, got {:?}", stats.connections);
+    };
+    assert_eq!(connection.messages_sent, NUM_MESSAGES as u64);
+    assert!(connection.substreams_opened >= 1);
+    assert!(
+        connection.reuse_factor().expect("substreams were opened") > 1.0,
+        "batched messages should share substreams: {connection:?}"
+    );
+    assert_eq!(stats.reuse_factor(), connection.reuse_factor());
+}
+
+#[tokio::test]
+async fn queued_messages_fail_when_dial_times_out() {
+    const DIAL_TIMEOUT: Duration = Duration::from_millis(500);
+    let config = Config::builder()
+        .dial_timeout(Some(DIAL_TIMEOUT))
+        .build()
+        .expect("valid config");
+    let mut sender = new_swarm_with_config::<TestCodec>(config);
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+
+    let address = listen(&mut receiver).await;
+
+    // The receiver is no longer polled, so the dial stalls in the connection upgrade without
+    // succeeding or failing
+    let message_ids = [
+        sender
+            .behaviour_mut()
+            .send_message_to_addr(receiver_id, address, b"first".to_vec()),
+        sender
+            .behaviour_mut()
+            .send_message(receiver_id, b"second".to_vec()),
+    ];
+
+    let started = tokio::time::Instant::now();
+    let failures = async {
+        let mut failed = Vec::new();
+        while failed.len() < message_ids.len() {
+            match sender.select_next_some().await {
+                SwarmEvent::Behaviour(Event::OutboundFailure {
+                    peer_id,
+                    message_id,
+                    error,
+                }) => {
+                    assert_eq!(peer_id, receiver_id);
+                    assert!(
+                        matches!(error, Error::DialTimeout),
+                        "unexpected error {error}"
+                    );
+                    failed.push(message_id);
+                }
+                SwarmEvent::ConnectionEstablished { .. } => panic!("dial was expected to stall"),
+                _ => {}
+            }
+        }
+        failed
+    };
+    let failed = tokio::time::timeout(Duration::from_secs(5), failures)
+        .await
+        .expect("messages did not fail after the dial timeout");
+
+    assert!(started.elapsed() >= DIAL_TIMEOUT);
+    assert_eq!(failed, message_ids);
+    drop(receiver);
+}
+
+#[tokio::test]
+async fn sent_messages_are_inflight_until_sent() {
+    let mut sender = new_swarm();
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    let mut message_ids = vec![
+        sender
+            .behaviour_mut()
+            .send_message(receiver_id, b"first".to_vec()),
+        sender
+            .behaviour_mut()
+            .send_message(receiver_id, b"second".to_vec()),
+    ];
+    let mut inflight = sender
+        .behaviour()
+        .inflight_messages(&receiver_id)
+        .collect::<Vec<_>>();
+    inflight.sort();
+    message_ids.sort();
+    assert_eq!(inflight, message_ids);
+
+    let exchange = async {
+        let mut num_sent = 0;
+        while num_sent < message_ids.len() {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::MessageSent { message_id, .. }) => {
+                        assert!(sender
+                            .behaviour()
+                            .inflight_messages(&receiver_id)
+                            .all(|id| id != message_id));
+                        num_sent += 1;
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { error, .. }) => {
+                        panic!("failed to send message: {error}");
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("sending timed out");
+
+    assert_eq!(
+        sender.behaviour().inflight_messages(&receiver_id).count(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn connection_pending_messages_shrink_as_sends_complete() {
+    const UNSUPPORTED: StreamProtocol = StreamProtocol::new("/messaging-test/2.0.0");
+
+    let mut sender =
+        new_swarm_with_protocols::<TestCodec>(vec![PROTOCOL, UNSUPPORTED], Config::default());
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    let sent_id = sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"hello".to_vec());
+    let failed_id = sender.behaviour_mut().send_message_with_protocol(
+        receiver_id,
+        UNSUPPORTED,
+        b"hello".to_vec(),
+    );
+    let num_pending = |sender: &Swarm<Behaviour<TestCodec>>| {
+        let stats = sender.behaviour().peer_stats(&receiver_id);
+        assert_eq!(stats.num_queued, 0);
+        stats
+            .connections
+            .iter()
+            .map(|conn| conn.num_pending)
+            .sum::<usize>()
+    };
+    assert_eq!(num_pending(&sender), 2);
+
+    let exchange = async {
+        let mut is_sent = false;
+        let mut is_failed = false;
+        while !is_sent || !is_failed {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::MessageSent { message_id, .. }) => {
+                        assert_eq!(message_id, sent_id);
+                        is_sent = true;
+                        assert_eq!(num_pending(&sender), usize::from(!is_failed));
+                    }
+                    SwarmEvent::Behaviour(Event::OutboundFailure { message_id, .. }) => {
+                        assert_eq!(message_id, failed_id);
+                        is_failed = true;
+                        assert_eq!(num_pending(&sender), usize::from(!is_sent));
+                    }
+                    _ => {}
+                },
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("sending timed out");
+}
+
+/// Closes the connection to the receiver while a message to it is still waiting to be handed to
+/// the connection handler, returning the event that resolves the message.
+async fn send_racing_close(requeue_undelivered: bool) -> Event<Vec<u8>> {
+    let config = Config::builder()
+        .event_stream_capacity(1)
+        .requeue_undelivered(requeue_undelivered)
+        .build()
+        .expect("valid config");
+    let mut sender = new_swarm_with_config::<TestCodec>(config);
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+
+    let address = listen(&mut receiver).await;
+
+    // Connect by sending a message, which also lets the behaviour dial the receiver again
+    sender
+        .behaviour_mut()
+        .send_message_to_addr(receiver_id, address, b"hello".to_vec());
+    let send = async {
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::MessageSent { .. }) = event {
+                        break;
+                    }
+                }
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), send)
+        .await
+        .expect("sending timed out");
+
+    // Events are held back while the unread event stream is full, so the message stays queued in
+    // the behaviour behind them until after the connection has closed
+    let mut events = Box::pin(sender.behaviour_mut().event_stream());
+    for _ in 0..3 {
+        sender.behaviour_mut().flush_peer(&PeerId::random());
+    }
+    let message_id = sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"racing".to_vec());
+    sender
+        .disconnect_peer_id(receiver_id)
+        .expect("connected to receiver");
+
+    let close = async {
+        loop {
+            tokio::select! {
+                event = sender.select_next_some() => {
+                    if let SwarmEvent::ConnectionClosed { .. } = event {
+                        break;
+                    }
+                }
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), close)
+        .await
+        .expect("closing timed out");
+
+    let outcome = async {
+        loop {
+            tokio::select! {
+                event = events.next() => {
+                    let event = event.expect("event stream ended");
+                    if let Event::MessageSent { message_id: id, .. }
+                    | Event::OutboundFailure { message_id: id, .. } = &event
+                    {
+                        if *id == message_id {
+                            break event;
+                        }
+                    }
+                    // The sender has no inbound streams, so an inbound failure is a misreported
+                    // outbound message
+                    if let Event::InboundFailure { .. } = &event {
+                        break event;
+                    }
+                }
+                _ = sender.select_next_some() => {}
+                _ = receiver.select_next_some() => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), outcome)
+        .await
+        .expect("message was neither sent nor failed")
+}
+
+#[tokio::test]
+async fn message_racing_connection_close_fails() {
+    let event = send_racing_close(false).await;
+    assert!(
+        matches!(
+            event,
+            Event::OutboundFailure {
+                error: Error::ConnectionClosed,
+                ..
+            }
+        ),
+        "unexpected event {event:?}"
+    );
+}
+
+#[tokio::test]
+async fn message_racing_connection_close_is_requeued() {
+    let event = send_racing_close(true).await;
+    assert!(
+        matches!(event, Event::MessageSent { .. }),
+        "unexpected event {event:?}"
+    );
+}
+
+#[tokio::test]
+async fn handler_writes_the_buffer_of_a_codec_that_prefers_bytes() {
+    /// Decodes like [`TestCodec`], but can only encode through [`Codec::encode_to_bytes`].
+    #[derive(Debug, Clone, Default)]
+    struct BufferCodec;
+
+    #[async_trait]
+    impl Codec for BufferCodec {
+        type Message = Vec<u8>;
+
+        async fn decode_from<R>(&mut self, reader: &mut R) -> io::Result<Self::Message>
+        where
+            R: AsyncRead + Unpin + Send,
+        {
+            TestCodec.decode_from(reader).await
+        }
+
+        async fn encode_to<W>(&mut self, _writer: &mut W, _message: Self::Message) -> io::Result<()>
+        where
+            W: AsyncWrite + Unpin + Send,
+        {
+            Err(io::Error::other("encode_to should not be called"))
+        }
+
+        async fn encode_to_bytes(&mut self, message: Self::Message) -> io::Result<bytes::Bytes> {
+            Ok(message.into())
+        }
+
+        fn prefers_bytes(&self) -> bool {
+            true
+        }
+    }
+
+    let mut sender = new_swarm_with_config::<BufferCodec>(Config::default());
+    let mut receiver = new_swarm();
+    connect(&mut sender, &mut receiver).await;
+
+    let receiver_id = *receiver.local_peer_id();
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"hello".to_vec());
+    let (message, _) = receive_one(&mut sender, &mut receiver).await;
+    assert_eq!(message, b"hello");
+}
+
+#[cfg(feature = "bytes")]
+#[tokio::test]
+async fn corrupt_inbound_frame_is_reported_with_decode_diagnostics() {
+    use libp2p_messaging::bytes::BytesCodec;
+
+    let mut sender = new_swarm_with_config::<RawCodec>(Config::default());
+    let mut receiver = new_swarm_with_config::<BytesCodec>(
+        Config::builder()
+            .decode_diagnostics(true)
+            .build()
+            .expect("valid config"),
+    );
+    let receiver_id = *receiver.local_peer_id();
+
+    connect(&mut sender, &mut receiver).await;
+
+    // A length prefix announcing more bytes than the stream carries before it closes
+    let frame = [&100u32.to_be_bytes()[..], b"truncated"].concat();
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, frame.clone());
+
+    let exchange = async {
+        loop {
+            tokio::select! {
+                _ = sender.select_next_some() => {}
+                event = receiver.select_next_some() => match event {
+                    SwarmEvent::Behaviour(Event::InboundFailure { error, .. }) => return error,
+                    SwarmEvent::Behaviour(Event::ReceivedMessage { .. }) => {
+                        panic!("corrupt frame was received");
+                    }
+                    _ => {}
+                },
+            }
+        }
+    };
+    let error = tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out");
+    let Error::CodecError { diagnostics, .. } = error else {
+        panic!("unexpected error: {error}");
+    };
+    assert_eq!(diagnostics.frame_len, Some(100));
+    assert_eq!(diagnostics.bytes_read, frame.len());
+    assert_eq!(diagnostics.snippet, frame);
+}
+
+#[tokio::test]
+async fn behaviour_with_a_closure_map_codec_sends_foreign_messages() {
+    use libp2p_messaging::MapCodec;
+
+    let codec = MapCodec::from_fns(
+        TestCodec,
+        |message: &std::net::Ipv4Addr| message.octets().to_vec(),
+        |bytes: Vec<u8>| {
+            <[u8; 4]>::try_from(bytes)
+                .map(std::net::Ipv4Addr::from)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not an address"))
+        },
+    );
+    let mut sender = new_swarm_with_behaviour(
+        Behaviour::with_codec([PROTOCOL], codec, Config::default()).expect("valid config"),
+    );
+    let mut receiver = new_swarm();
+    connect(&mut sender, &mut receiver).await;
+
+    let receiver_id = *receiver.local_peer_id();
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, std::net::Ipv4Addr::LOCALHOST);
+    let (message, _) = receive_one(&mut sender, &mut receiver).await;
+    assert_eq!(message, [127, 0, 0, 1]);
+}
+
+#[tokio::test]
+async fn high_priority_connection_outlives_a_low_priority_one_while_idle() {
+    const GRACE: Duration = Duration::from_millis(300);
+
+    let behaviour = Behaviour::<TestCodec>::new(
+        [PROTOCOL],
+        Config::builder()
+            .idle_connection_grace(GRACE)
+            .build()
+            .expect("valid config"),
+    )
+    .expect("valid config");
+    let mut peer = new_swarm_with_idle_timeout(behaviour, Duration::from_millis(100));
+    // The remotes keep their side of the connections alive for longer than the peer
+    let mut high_remote = new_swarm();
+    let mut low_remote = new_swarm();
+    let high_id = *high_remote.local_peer_id();
+    let low_id = *low_remote.local_peer_id();
+
+    peer.behaviour_mut()
+        .set_peer_priority(high_id, ConnectionPriority::High);
+    peer.behaviour_mut()
+        .set_peer_priority(low_id, ConnectionPriority::Low);
+    connect(&mut peer, &mut high_remote).await;
+    connect(&mut peer, &mut low_remote).await;
+    let idle_since = Instant::now();
+
+    let lifetimes = async {
+        let mut high_lifetime = None;
+        let mut low_lifetime = None;
+        loop {
+            tokio::select! {
+                event = peer.select_next_some() => {
+                    if let SwarmEvent::ConnectionClosed { peer_id, .. } = event {
+                        if peer_id == high_id {
+                            high_lifetime = Some(idle_since.elapsed());
+                        } else if peer_id == low_id {
+                            low_lifetime = Some(idle_since.elapsed());
+                        }
+                    }
+                }
+                _ = high_remote.select_next_some() => {}
+                _ = low_remote.select_next_some() => {}
+            }
+            if let (Some(high), Some(low)) = (high_lifetime, low_lifetime) {
+                return (high, low);
+            }
+        }
+    };
+    let (high, low) = tokio::time::timeout(Duration::from_secs(10), lifetimes)
+        .await
+        .expect("idle connections were not closed");
+    assert!(low < GRACE, "low priority connection lived for {low:?}");
+    // The grace of a high priority connection is longer, but still bounded
+    assert!(
+        high >= GRACE * 4 && high < GRACE * 4 + Duration::from_secs(2),
+        "high priority connection lived for {high:?}"
+    );
+}
+
+/// Sends a message while the sender's only connection to the receiver is saturated by a message
+/// that is slow to encode. Returns the messages the receiver got in order, the number of
+/// connections the sender opened meanwhile, and the error the second message failed with, if any.
+async fn send_while_saturated(policy: SaturationPolicy) -> (Vec<Vec<u8>>, usize, Option<Error>) {
+    let mut sender = new_swarm_with_config::<SlowEncodeCodec>(
+        Config::builder()
+            .max_concurrent_streams(1)
+            .remembered_addresses(1)
+            .saturation_policy(policy)
+            .build()
+            .expect("valid config"),
+    );
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+    connect(&mut sender, &mut receiver).await;
+
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"slow".to_vec());
+    let second_id = sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"second".to_vec());
+
+    let exchange = async {
+        let mut received = Vec::new();
+        let mut num_connections = 0;
+        let mut failure = None;
+        while !received.contains(&b"slow".to_vec())
+            || (failure.is_none() && !received.contains(&b"second".to_vec()))
+        {
+            tokio::select! {
+                event = sender.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { .. } => num_connections += 1,
+                    SwarmEvent::Behaviour(Event::OutboundFailure {
+                        message_id, error, ..
+                    }) => {
+                        assert_eq!(message_id, second_id);
+                        failure = Some(error);
+                    }
+                    _ => {}
+                },
+                event = receiver.select_next_some() => {
+                    if let SwarmEvent::Behaviour(Event::ReceivedMessage { message, .. }) = event {
+                        received.push(message);
+                    }
+                }
+            }
+        }
+        (received, num_connections, failure)
+    };
+    tokio::time::timeout(Duration::from_secs(10), exchange)
+        .await
+        .expect("message exchange timed out")
+}
+
+#[tokio::test]
+async fn saturated_connection_queues_messages_with_queue_policy() {
+    let (received, num_connections, failure) = send_while_saturated(SaturationPolicy::Queue).await;
+    assert_eq!(received, [b"slow".to_vec(), b"second".to_vec()]);
+    assert_eq!(num_connections, 0);
+    assert!(failure.is_none(), "unexpected failure: {failure:?}");
+}
+
+#[tokio::test]
+async fn saturated_connection_dials_remembered_address_with_open_new_connection_policy() {
+    let (received, num_connections, failure) =
+        send_while_saturated(SaturationPolicy::OpenNewConnection { max_connections: 2 }).await;
+    // The second message is sent on the new connection while the first is still being encoded
+    assert_eq!(received, [b"second".to_vec(), b"slow".to_vec()]);
+    assert_eq!(num_connections, 1);
+    assert!(failure.is_none(), "unexpected failure: {failure:?}");
+}
+
+#[tokio::test]
+async fn saturated_connection_fails_messages_with_fail_policy() {
+    let (received, num_connections, failure) = send_while_saturated(SaturationPolicy::Fail).await;
+    assert_eq!(received, [b"slow".to_vec()]);
+    assert_eq!(num_connections, 0);
+    assert!(
+        matches!(failure, Some(Error::AllConnectionsSaturated)),
+        "unexpected failure: {failure:?}"
+    );
+}
+
+#[tokio::test]
+async fn drain_pending_returns_queued_and_in_flight_messages() {
+    let mut sender = new_swarm_with_config::<SlowEncodeCodec>(
+        Config::builder()
+            .dial_timeout(Some(Duration::from_secs(5)))
+            .build()
+            .expect("valid config"),
+    );
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+    connect(&mut sender, &mut receiver).await;
+
+    // Queued for peers that are not connected, and in flight on the connection to the receiver
+    let offline = [PeerId::random(), PeerId::random()];
+    let mut expected = vec![
+        (
+            offline[0],
+            sender
+                .behaviour_mut()
+                .send_message(offline[0], b"first".to_vec()),
+        ),
+        (
+            offline[0],
+            sender
+                .behaviour_mut()
+                .send_message(offline[0], b"second".to_vec()),
+        ),
+        (
+            offline[1],
+            sender
+                .behaviour_mut()
+                .send_message(offline[1], b"third".to_vec()),
+        ),
+        (
+            receiver_id,
+            sender
+                .behaviour_mut()
+                .send_message(receiver_id, b"slow".to_vec()),
+        ),
+    ];
+    assert_eq!(sender.behaviour().peers_with_pending().len(), 3);
+
+    let mut drained = sender.behaviour_mut().drain_pending();
+    drained.sort();
+    expected.sort();
+    assert_eq!(drained, expected);
+    for peer_id in offline.iter().chain([&receiver_id]) {
+        assert!(!sender.behaviour().has_pending(peer_id));
+    }
+    assert!(sender.behaviour().peers_with_pending().is_empty());
+}
+
+#[tokio::test]
+async fn peers_with_pending_reports_queued_and_in_flight_messages() {
+    let mut sender = new_swarm_with_config::<SlowEncodeCodec>(Config::default());
+    let mut receiver = new_swarm();
+    let receiver_id = *receiver.local_peer_id();
+    connect(&mut sender, &mut receiver).await;
+    assert!(sender.behaviour().peers_with_pending().is_empty());
+
+    // Queued for a peer that is not connected, and in flight on the connection to the receiver
+    let offline = PeerId::random();
+    sender
+        .behaviour_mut()
+        .send_message(offline, b"queued".to_vec());
+    sender
+        .behaviour_mut()
+        .send_message(receiver_id, b"slow".to_vec());
+
+    let mut peers = sender.behaviour().peers_with_pending();
+    peers.sort();
+    let mut expected = vec![offline, receiver_id];
+    expected.sort();
+    assert_eq!(peers, expected);
+
+    // Once sent, the message is no longer in flight
+    wait_until_sent(&mut sender, &mut receiver).await;
+    assert!(!sender
+        .behaviour()
+        .peers_with_pending()
+        .contains(&receiver_id));
+}